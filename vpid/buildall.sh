@@ -74,6 +74,9 @@ printf "Start building vpid + vpidctl version:"
 VPID_VERSION=$(cargo pkgid -v --manifest-path ./vpid/Cargo.toml | cut -d \# -f 2)
 echo "$VPID_VERSION"
 
+# Firmware headers used by vpi build.rs to verify the register map
+export VPI_FIRMWARE_DIR=$(cd .. && pwd)/firmware_borosVPi
+
 echo "Building release versions...."
 for i in "${ARCHS[@]}"
 do
@@ -1,3 +1,5 @@
 #!/bin/bash
-
-python -m serial.tools.miniterm /dev/ttyS0 9600
+# Decode firmware debug traces (firmware built with VPI_TRACES=Yes)
+# interleaved with the I2C transactions of vpid (RUST_LOG=info,vpi::i2c=trace)
+# Use: monitor.sh [tty] [baud]
+journalctl -fu vpid -o cat | vpidctl uart -p ${1:-/dev/ttyS0} -b ${2:-9600} -l -
@@ -0,0 +1,114 @@
+/**
+ * @file vpi.h
+ * @author LDV
+ * @brief C ABI of the vpi driver (libvpi_ffi). Drive a VPi board without vpid.
+ *
+ * All functions returning int return VPI_OK or a negative VPI_ERR_* code.
+ * The message of the last error of the calling thread is returned by vpi_last_error().
+ * A panic of the driver is returned as the function error code (VPI_ERR_I2C, or
+ * VPI_ERR_UPLOAD for the uploader) with a "panic: ..." message.
+ * A handle must not be used from several threads at the same time.
+ *
+ * Python ctypes:
+ *   lib = ctypes.CDLL("libvpi_ffi.so")
+ *   lib.vpi_open.restype = ctypes.c_void_p
+ *   h = ctypes.c_void_p(lib.vpi_open(b"/dev/i2c-1", 0))
+ *   buf = ctypes.create_string_buffer(256)
+ *   lib.vpi_run(h, b"led blink", buf, 256)
+ */
+#ifndef _VPI_H_
+#define _VPI_H_
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Error codes
+#define VPI_OK          (0)
+#define VPI_ERR_ARG     (-1)  ///< Null pointer or invalid argument
+#define VPI_ERR_UTF8    (-2)  ///< String is not valid UTF-8
+#define VPI_ERR_I2C     (-3)  ///< I2C communication error
+#define VPI_ERR_CMD     (-4)  ///< Unknown or invalid command
+#define VPI_ERR_BUFFER  (-5)  ///< Output buffer too small
+#define VPI_ERR_UPLOAD  (-6)  ///< Firmware upload failed
+
+/** @brief Opaque handle of an opened board */
+typedef struct vpi_handle vpi_handle;
+
+/** @brief Board status. Flags are 0/1 */
+typedef struct vpi_status_t {
+  uint8_t has_click;
+  uint8_t has_rpm;
+  uint8_t has_error;
+  uint8_t has_irq;
+  uint8_t is_running;
+  uint8_t is_wdg_enabled;
+  uint8_t is_wake_enabled;
+  uint8_t is_wake_irq_enabled;
+  uint8_t out_value;
+  uint8_t integrity;
+  uint8_t recover_type;
+  uint8_t crc;
+  int32_t pwr_short;
+  int32_t pwr_long;
+  int32_t aux_short;
+  int32_t aux_long;
+  int32_t rpm;
+  int32_t error_count;
+} vpi_status_t;
+
+/** @brief Driver statistics */
+typedef struct vpi_stats_t {
+  uint32_t retries;
+  uint32_t recovers;
+  uint32_t i2c_errors;
+  uint32_t crc_errors;
+  uint64_t status_checks;
+} vpi_stats_t;
+
+/** @brief Firmware upload progress callback (blocks done, total blocks, user data) */
+typedef void (*vpi_progress_cb)(uint32_t done, uint32_t total, void *user);
+
+/** @brief Message of the last error in this thread. Valid until next call in the thread. */
+const char *vpi_last_error(void);
+
+/** @brief Open the board at dev (/dev/i2c-X) and i2c addr (0 for default 0x33). NULL on error. */
+vpi_handle *vpi_open(const char *dev, uint8_t addr);
+
+/** @brief Close the board and free the handle. NULL is ignored. */
+void vpi_close(vpi_handle *h);
+
+/**
+ * @brief Run a command by string as in `vpidctl dcmd` (e.g. "fan 128", "led blink").
+ * The JSON response {"result":..,"data":..} is copied to out (NUL terminated).
+ * @return length of the response or a negative error code.
+ */
+int vpi_run(vpi_handle *h, const char *cmd, char *out, size_t len);
+
+/** @brief Read the board status */
+int vpi_status(vpi_handle *h, vpi_status_t *st);
+
+/** @brief Get the driver statistics */
+int vpi_stats(vpi_handle *h, vpi_stats_t *st);
+
+/**
+ * @brief Copy the 96-bit board UUID as hex string (25 bytes buffer min).
+ * @return length of the UUID or a negative error code.
+ */
+int vpi_uuid(vpi_handle *h, char *out, size_t len);
+
+/**
+ * @brief Upload a firmware .bin with the i2c bootloader at addr (0 for default 0x22)
+ * resetting the board with gpio rst_pin. cb (optional) receives the progress.
+ */
+int vpi_firmware_upload(const char *dev, uint8_t addr, const char *file, uint16_t rst_pin,
+                        vpi_progress_cb cb, void *user);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,381 @@
+//! C ABI for the vpi driver.
+//! Exposes an opaque handle to drive a VPi board from C or Python `ctypes`
+//! without `vpid`. See `include/vpi.h` for the C declarations.
+//!
+//! All functions return `VPI_OK` (0) or a negative error code. The message of the
+//! last error of the calling thread is available with `vpi_last_error()`.
+//! Panics don't unwind in to C: they are returned as the error code of the failed
+//! operation (`VPI_ERR_I2C` for the board, `VPI_ERR_UPLOAD` for the uploader).
+//! A handle must not be shared between threads without external locking.
+//!
+//! # Safety
+//! Pointers must be NULL or valid: strings NUL terminated, buffers of at least the
+//! given length and handles returned by `vpi_open` not yet closed.
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+use i2cdev::linux::LinuxI2CError;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use vpi::cmd::{VpiCmd, VpiCmdOutput};
+use vpi::uploader::UploadStep;
+use vpi::{Vpi, VpiStats, VpiStatus};
+
+// Error codes (keep in sync with include/vpi.h)
+pub const VPI_OK: c_int = 0;
+/// Null pointer or invalid argument
+pub const VPI_ERR_ARG: c_int = -1;
+/// String is not valid UTF-8
+pub const VPI_ERR_UTF8: c_int = -2;
+/// I2C communication error
+pub const VPI_ERR_I2C: c_int = -3;
+/// Unknown or invalid command
+pub const VPI_ERR_CMD: c_int = -4;
+/// Output buffer too small
+pub const VPI_ERR_BUFFER: c_int = -5;
+/// Firmware upload failed
+pub const VPI_ERR_UPLOAD: c_int = -6;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Store the message of the last error and return the code
+fn set_error(code: c_int, msg: &str) -> c_int {
+    let cmsg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = cmsg);
+    code
+}
+
+/// Run `f` catching a panic, stored as last error and returned as `code`
+fn guard<F: FnOnce() -> c_int>(code: c_int, f: F) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|p| panic_error(code, p))
+}
+
+/// Store the message of a caught panic and return `code`
+fn panic_error(code: c_int, p: Box<dyn std::any::Any + Send>) -> c_int {
+    let msg = p
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| p.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    set_error(code, &format!("panic: {}", msg))
+}
+
+fn i2c_error(e: LinuxI2CError) -> c_int {
+    set_error(VPI_ERR_I2C, &e.to_string())
+}
+
+/// Convert a C string to &str
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(set_error(VPI_ERR_ARG, "null string"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| set_error(VPI_ERR_UTF8, &e.to_string()))
+}
+
+/// Copy `s` in to the C buffer `out` of `len` bytes (NUL terminated).
+/// Returns the length of the string or `VPI_ERR_BUFFER`.
+unsafe fn copy_out(s: &str, out: *mut c_char, len: usize) -> c_int {
+    if out.is_null() {
+        return set_error(VPI_ERR_ARG, "null output buffer");
+    }
+    if s.len() + 1 > len {
+        return set_error(
+            VPI_ERR_BUFFER,
+            &format!("output buffer too small, {} bytes required", s.len() + 1),
+        );
+    }
+    ptr::copy_nonoverlapping(s.as_ptr(), out as *mut u8, s.len());
+    *out.add(s.len()) = 0;
+    s.len() as c_int
+}
+
+/// Opaque handle of an opened board
+pub struct vpi_handle {
+    vpi: Vpi,
+}
+
+/// Board status (see `VpiStatus`)
+#[repr(C)]
+#[derive(Default)]
+pub struct vpi_status_t {
+    pub has_click: u8,
+    pub has_rpm: u8,
+    pub has_error: u8,
+    pub has_irq: u8,
+    pub is_running: u8,
+    pub is_wdg_enabled: u8,
+    pub is_wake_enabled: u8,
+    pub is_wake_irq_enabled: u8,
+    pub out_value: u8,
+    pub integrity: u8,
+    pub recover_type: u8,
+    pub crc: u8,
+    pub pwr_short: i32,
+    pub pwr_long: i32,
+    pub aux_short: i32,
+    pub aux_long: i32,
+    pub rpm: i32,
+    pub error_count: i32,
+}
+
+impl From<VpiStatus> for vpi_status_t {
+    fn from(s: VpiStatus) -> Self {
+        vpi_status_t {
+            has_click: s.has_click as u8,
+            has_rpm: s.has_rpm as u8,
+            has_error: s.has_error as u8,
+            has_irq: s.has_irq as u8,
+            is_running: s.is_running as u8,
+            is_wdg_enabled: s.is_wdg_enabled as u8,
+            is_wake_enabled: s.is_wake_enabled as u8,
+            is_wake_irq_enabled: s.is_wake_irq_enabled as u8,
+            out_value: s.out_value as u8,
+            integrity: s.integrity as u8,
+            recover_type: s.recover_type,
+            crc: s.crc,
+            pwr_short: s.pwr_short,
+            pwr_long: s.pwr_long,
+            aux_short: s.aux_short,
+            aux_long: s.aux_long,
+            rpm: s.rpm,
+            error_count: s.error_count,
+        }
+    }
+}
+
+/// Driver statistics (see `VpiStats`)
+#[repr(C)]
+#[derive(Default)]
+pub struct vpi_stats_t {
+    pub retries: u32,
+    pub recovers: u32,
+    pub i2c_errors: u32,
+    pub crc_errors: u32,
+    pub status_checks: u64,
+}
+
+impl From<VpiStats> for vpi_stats_t {
+    fn from(s: VpiStats) -> Self {
+        vpi_stats_t {
+            retries: s.retries,
+            recovers: s.recovers,
+            i2c_errors: s.i2c_errors,
+            crc_errors: s.crc_errors,
+            status_checks: s.status_checks,
+        }
+    }
+}
+
+/// Firmware upload progress callback: (blocks done, total blocks, user data)
+pub type vpi_progress_cb = Option<extern "C" fn(u32, u32, *mut c_void)>;
+
+/// Message of the last error in this thread. Valid until next call in the thread.
+#[no_mangle]
+pub extern "C" fn vpi_last_error() -> *const c_char {
+    catch_unwind(|| LAST_ERROR.with(|e| e.borrow().as_ptr())).unwrap_or(ptr::null())
+}
+
+/// Open the board at `dev` (/dev/i2c-X) and i2c `addr` (0 for default 0x33).
+/// Returns NULL on error.
+#[no_mangle]
+pub unsafe extern "C" fn vpi_open(dev: *const c_char, addr: u8) -> *mut vpi_handle {
+    let res = catch_unwind(|| {
+        let dev = match to_str(dev) {
+            Ok(d) => d,
+            Err(_) => return ptr::null_mut(),
+        };
+        let addr = if addr == 0 { None } else { Some(addr as u16) };
+        let mut vpi = Vpi::new(addr, false);
+        match vpi.open(&PathBuf::from(dev)) {
+            Ok(_) => Box::into_raw(Box::new(vpi_handle { vpi })),
+            Err(e) => {
+                i2c_error(e);
+                ptr::null_mut()
+            }
+        }
+    });
+    res.unwrap_or_else(|p| {
+        panic_error(VPI_ERR_I2C, p);
+        ptr::null_mut()
+    })
+}
+
+/// Close the board and free the handle. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn vpi_close(h: *mut vpi_handle) {
+    if !h.is_null() {
+        guard(VPI_ERR_I2C, || {
+            drop(Box::from_raw(h));
+            VPI_OK
+        });
+    }
+}
+
+/// Run a command by string as in `vpidctl dcmd` (e.g. "fan 128", "led blink").
+/// The JSON response `{"result":..,"data":..}` is copied to `out` of `len` bytes.
+/// Returns the length of the response or a negative error code.
+#[no_mangle]
+pub unsafe extern "C" fn vpi_run(
+    h: *mut vpi_handle,
+    cmd: *const c_char,
+    out: *mut c_char,
+    len: usize,
+) -> c_int {
+    guard(VPI_ERR_I2C, || {
+        let h = match h.as_mut() {
+            Some(h) => h,
+            None => return set_error(VPI_ERR_ARG, "null handle"),
+        };
+        let cmd = match to_str(cmd) {
+            Ok(c) => c.to_string(),
+            Err(code) => return code,
+        };
+        let basic = match VpiCmd::from_string(&cmd) {
+            Some(b) => b,
+            None => return set_error(VPI_ERR_CMD, &format!("invalid command '{}'", cmd)),
+        };
+        match h.vpi.run(&basic, true) {
+            Ok(VpiCmdOutput::Json(js)) => copy_out(&js, out, len),
+            Ok(other) => copy_out(&other.to_json().to_string(), out, len),
+            Err(e) => i2c_error(e),
+        }
+    })
+}
+
+/// Read the board status in to `st`
+#[no_mangle]
+pub unsafe extern "C" fn vpi_status(h: *mut vpi_handle, st: *mut vpi_status_t) -> c_int {
+    guard(VPI_ERR_I2C, || match (h.as_mut(), st.as_mut()) {
+        (Some(h), Some(st)) => match h.vpi.check_status(0) {
+            Ok(s) => {
+                *st = s.into();
+                VPI_OK
+            }
+            Err(e) => i2c_error(e),
+        },
+        _ => set_error(VPI_ERR_ARG, "null handle or status"),
+    })
+}
+
+/// Copy the driver statistics in to `st`
+#[no_mangle]
+pub unsafe extern "C" fn vpi_stats(h: *mut vpi_handle, st: *mut vpi_stats_t) -> c_int {
+    guard(VPI_ERR_I2C, || match (h.as_ref(), st.as_mut()) {
+        (Some(h), Some(st)) => {
+            *st = h.vpi.get_stats().into();
+            VPI_OK
+        }
+        _ => set_error(VPI_ERR_ARG, "null handle or stats"),
+    })
+}
+
+/// Copy the 96-bit board UUID as hex string in to `out` (25 bytes min).
+/// Returns the length of the UUID or a negative error code.
+#[no_mangle]
+pub unsafe extern "C" fn vpi_uuid(h: *mut vpi_handle, out: *mut c_char, len: usize) -> c_int {
+    guard(VPI_ERR_I2C, || match h.as_ref() {
+        Some(h) => copy_out(&h.vpi.get_uuid(), out, len),
+        None => set_error(VPI_ERR_ARG, "null handle"),
+    })
+}
+
+/// Upload a firmware `.bin` file with the i2c bootloader at `addr` (0 for default 0x22)
+/// resetting the board with gpio `rst_pin`. `cb` (optional) is called with the progress.
+#[no_mangle]
+pub unsafe extern "C" fn vpi_firmware_upload(
+    dev: *const c_char,
+    addr: u8,
+    file: *const c_char,
+    rst_pin: u16,
+    cb: vpi_progress_cb,
+    user: *mut c_void,
+) -> c_int {
+    guard(VPI_ERR_UPLOAD, || {
+        let (dev, file) = match (to_str(dev), to_str(file)) {
+            (Ok(d), Ok(f)) => (PathBuf::from(d), PathBuf::from(f)),
+            (Err(code), _) | (_, Err(code)) => return code,
+        };
+        let addr = if addr == 0 { 0x22 } else { addr };
+        let res = vpi::uploader::upload_with_progress(addr, &dev, &file, rst_pin, |step| {
+            if let (Some(f), UploadStep::Block { done, total }) = (cb, step) {
+                f(done, total, user);
+            }
+        });
+        vpi::uploader::unexport(rst_pin);
+        match res {
+            Ok(()) => VPI_OK,
+            Err(e) => set_error(VPI_ERR_UPLOAD, &e.to_string()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(vpi_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn null_handles() {
+        let mut out = [0 as c_char; 32];
+        let mut st = vpi_status_t::default();
+        let mut stats = vpi_stats_t::default();
+        let cmd = CString::new("fan 100").unwrap();
+        unsafe {
+            assert_eq!(
+                vpi_run(ptr::null_mut(), cmd.as_ptr(), out.as_mut_ptr(), out.len()),
+                VPI_ERR_ARG
+            );
+            assert_eq!(last_error(), "null handle");
+            assert_eq!(vpi_status(ptr::null_mut(), &mut st), VPI_ERR_ARG);
+            assert_eq!(vpi_stats(ptr::null_mut(), &mut stats), VPI_ERR_ARG);
+            assert_eq!(last_error(), "null handle or stats");
+            assert_eq!(
+                vpi_uuid(ptr::null_mut(), out.as_mut_ptr(), out.len()),
+                VPI_ERR_ARG
+            );
+            assert!(vpi_open(ptr::null(), 0).is_null());
+            assert_eq!(last_error(), "null string");
+            vpi_close(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn error_codes() {
+        let dev = CString::new("/nonexistent/i2c-1").unwrap();
+        let bad = [0xffu8 as c_char, 0];
+        let mut out = [0 as c_char; 4];
+        unsafe {
+            assert!(vpi_open(dev.as_ptr(), 0).is_null());
+            assert!(!last_error().is_empty());
+            assert!(vpi_open(bad.as_ptr(), 0).is_null());
+            assert_eq!(to_str(bad.as_ptr()), Err(VPI_ERR_UTF8));
+            assert_eq!(copy_out("abc", out.as_mut_ptr(), out.len()), 3);
+            assert_eq!(CStr::from_ptr(out.as_ptr()).to_str(), Ok("abc"));
+            assert_eq!(
+                copy_out("abcd", out.as_mut_ptr(), out.len()),
+                VPI_ERR_BUFFER
+            );
+            assert_eq!(last_error(), "output buffer too small, 5 bytes required");
+            assert_eq!(copy_out("abc", ptr::null_mut(), 4), VPI_ERR_ARG);
+        }
+    }
+
+    #[test]
+    fn caught_panics() {
+        assert_eq!(guard(VPI_ERR_I2C, || panic!("bus lost")), VPI_ERR_I2C);
+        assert_eq!(last_error(), "panic: bus lost");
+        assert_eq!(guard(VPI_ERR_UPLOAD, || VPI_OK), VPI_OK);
+    }
+}
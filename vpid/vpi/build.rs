@@ -0,0 +1,141 @@
+//! Build script for vpi
+//! Parses the firmware register map `vpi_regs.h` and generates `vpi_regs.rs` with
+//! field offsets & sizes of `VPiRegs` and the values of the `#define` constants.
+//! `lib.rs` checks the hand written `VpiRegs` and constants against them at compile
+//! time, so a firmware change that moves a register fails the build.
+//!
+//! The header is searched in `$VPI_FIRMWARE_DIR/inc` or in the firmware folder of
+//! the repository. If not found (e.g. crate built out of the repository) the checks
+//! are skipped with a warning.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Locate vpi_regs.h
+fn header_path() -> PathBuf {
+    match env::var("VPI_FIRMWARE_DIR") {
+        Ok(dir) => Path::new(&dir).join("inc").join("vpi_regs.h"),
+        Err(_) => Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("../../firmware_borosVPi/inc/vpi_regs.h"),
+    }
+}
+
+/// Size in bytes of the C types used in the register map
+fn type_size(t: &str) -> Option<usize> {
+    match t {
+        "uint8_t" | "int8_t" | "char" => Some(1),
+        "uint16_t" | "int16_t" => Some(2),
+        "uint32_t" | "int32_t" => Some(4),
+        _ => None,
+    }
+}
+
+/// Parse the fields of `typedef struct VPIREGS {...} VPiRegs;` as (name,offset,size).
+/// SDCC does not pad structures for the STM8 so the layout is packed.
+fn parse_struct(h: &str) -> Result<Vec<(String, usize, usize)>, String> {
+    let start = h
+        .find("typedef struct VPIREGS")
+        .ok_or("VPIREGS struct not found")?;
+    let body = &h[start..];
+    let open = body.find('{').ok_or("VPIREGS struct not opened")?;
+    let close = body.find("} VPiRegs;").ok_or("VPIREGS struct not closed")?;
+    let mut fields = vec![];
+    let mut offset = 0usize;
+    for line in body[open + 1..close].lines() {
+        let line = line.split("//").next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let decl = line
+            .strip_suffix(';')
+            .ok_or(format!("Unexpected line in VPIREGS '{}'", line))?;
+        let mut it = decl.split_whitespace();
+        let ctype = it.next().ok_or(format!("No type in '{}'", line))?;
+        let rest: String = it.collect();
+        let size = type_size(ctype).ok_or(format!("Unknown type {} in '{}'", ctype, line))?;
+        let name_end = rest.find('[').unwrap_or(rest.len());
+        let name = rest[..name_end].to_string();
+        let mut len = size;
+        for dim in rest[name_end..].split(['[', ']']) {
+            if !dim.is_empty() {
+                len *= dim
+                    .parse::<usize>()
+                    .map_err(|_| format!("Bad array size in '{}'", line))?;
+            }
+        }
+        fields.push((name, offset, len));
+        offset += len;
+    }
+    Ok(fields)
+}
+
+/// Parse `#define NAME (value)` constants with a numeric or char value
+fn parse_defines(h: &str) -> Vec<(String, u32)> {
+    let mut defs = vec![];
+    for line in h.lines() {
+        let line = line.split("//").next().unwrap().trim();
+        let mut it = line.splitn(3, char::is_whitespace);
+        if it.next() != Some("#define") {
+            continue;
+        }
+        let name = match it.next() {
+            Some(n) if !n.contains('(') => n,
+            _ => continue, // Macro functions or empty
+        };
+        let value = it
+            .next()
+            .unwrap_or("")
+            .trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .trim();
+        let parsed = if value.len() == 3 && value.starts_with('\'') && value.ends_with('\'') {
+            Some(value.as_bytes()[1] as u32)
+        } else if let Some(hex) = value.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16).ok()
+        } else {
+            value.parse::<u32>().ok()
+        };
+        if let Some(v) = parsed {
+            defs.push((name.to_string(), v));
+        }
+    }
+    defs
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=VPI_FIRMWARE_DIR");
+    println!("cargo:rustc-check-cfg=cfg(vpi_regs_h)");
+    let header = header_path();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("vpi_regs.rs");
+    let h = match fs::read_to_string(&header) {
+        Ok(h) => h,
+        Err(e) => {
+            println!(
+                "cargo:warning=Firmware header {} not available ({}), register map not verified. Set VPI_FIRMWARE_DIR.",
+                header.display(),
+                e
+            );
+            fs::write(&out, "// vpi_regs.h not found\n").unwrap();
+            return;
+        }
+    };
+    println!("cargo:rerun-if-changed={}", header.display());
+    let fields = parse_struct(&h).unwrap_or_else(|e| panic!("{}: {}", header.display(), e));
+    let mut code = format!(
+        "// Generated by build.rs from {}. Do not edit.\n",
+        header.display()
+    );
+    let total: usize = fields.iter().map(|f| f.2).sum();
+    code += &format!("pub const VPIREGS_SIZE: usize = {};\n", total);
+    for (name, offset, size) in fields.iter() {
+        let up = name.to_uppercase();
+        code += &format!("pub const OFFSET_{}: usize = {};\n", up, offset);
+        code += &format!("pub const SIZE_{}: usize = {};\n", up, size);
+    }
+    for (name, value) in parse_defines(&h) {
+        code += &format!("pub const {}: u32 = {};\n", name, value);
+    }
+    fs::write(&out, code).unwrap();
+    println!("cargo:rustc-cfg=vpi_regs_h");
+}
@@ -138,7 +138,7 @@ impl VpiCmd {
             "watchdog" => {
                 if v.len() >= 2 && v[1].parse::<i32>().is_ok() {
                     let v = v[1].parse::<i32>().unwrap();
-                    if v >= 0 && v <= u8::max as i32 {
+                    if v >= 0 && v <= u8::MAX as i32 {
                         Some(VpiCmd::Wdg(v as u8))
                     } else {
                         None
@@ -150,7 +150,7 @@ impl VpiCmd {
             "wake" => {
                 if v.len() >= 2 && v[1].parse::<i32>().is_ok() {
                     let v = v[1].parse::<i32>().unwrap();
-                    if v >= 0 && v <= u16::max as i32 {
+                    if v >= 0 && v <= u16::MAX as i32 {
                         Some(VpiCmd::Wake(v as u16))
                     } else {
                         None
@@ -176,7 +176,7 @@ impl VpiCmd {
 
                     if v.len() >= 3 {
                         let tst = v[2].parse::<i32>().unwrap_or(-1);
-                        if tst >= 0 && tst <= u8::max as i32 {
+                        if tst >= 0 && tst <= u8::MAX as i32 {
                             val = tst as u8;
                         }
                     }
@@ -189,7 +189,7 @@ impl VpiCmd {
             "fan" => {
                 if v.len() >= 2 && v[1].parse::<i32>().is_ok() {
                     let tst = v[1].parse::<i32>().unwrap();
-                    if tst >= 0 && tst <= u8::max as i32 {
+                    if tst >= 0 && tst <= u8::MAX as i32 {
                         Some(VpiCmd::Fan(tst as u8))
                     } else {
                         None
@@ -213,7 +213,7 @@ impl VpiCmd {
 
                     if v.len() >= 3 {
                         let tst = v[2].parse::<i32>().unwrap_or(count as i32);
-                        if tst <= u8::max as i32 {
+                        if tst <= u8::MAX as i32 {
                             count = tst as u8;
                         }
                     }
@@ -239,7 +239,7 @@ impl VpiCmd {
             "divisor" => {
                 if v.len() >= 2 && v[1].parse::<i32>().is_ok() {
                     let v = v[1].parse::<i32>().unwrap();
-                    if v >= 0 && v <= u8::max as i32 {
+                    if v >= 0 && v <= u8::MAX as i32 {
                         Some(VpiCmd::Divisor(v as u8))
                     } else {
                         None
@@ -266,16 +266,16 @@ impl VpiCmd {
                     .map(|e| e.parse::<i32>().unwrap_or(-1))
                     .collect();
                 let mut t = VpiTimes::default();
-                if p.len() >= 1 && p[0] > 20 && p[0] <= u16::max as i32 {
+                if !p.is_empty() && p[0] > 20 && p[0] <= u16::MAX as i32 {
                     t.short_tm = p[0] as u16
                 }
-                if p.len() >= 2 && p[1] > 100 && p[1] <= u16::max as i32 {
+                if p.len() >= 2 && p[1] > 100 && p[1] <= u16::MAX as i32 {
                     t.space_tm = p[1] as u16
                 }
-                if p.len() >= 3 && p[2] > 0 && p[2] <= u8::max as i32 {
+                if p.len() >= 3 && p[2] > 0 && p[2] <= u8::MAX as i32 {
                     t.hold_tm = p[2] as u8
                 }
-                if p.len() >= 4 && p[3] > 0 && p[3] <= u8::max as i32 {
+                if p.len() >= 4 && p[3] > 0 && p[3] <= u8::MAX as i32 {
                     t.grace_tm = p[3] as u8
                 }
                 Some(VpiCmd::Timing(t))
@@ -0,0 +1,595 @@
+//! Decoder for the firmware debug UART stream.
+//! Firmware built with `VPI_TRACES=Yes` prints short traces via `DBG()` (see `dbg.h`)
+//! on the STM8 UART. Traces are not line based: they are concatenated tokens like
+//! `[BOOT:3]{0->1}{B}..D:0,1234U:0,1350[TR]`. This module opens the tty and turns
+//! the byte stream in to `FwEvent`s.
+//!
+use nix::libc;
+use nix::sys::termios::{self, BaudRate, SetArg};
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Default baud rate of the firmware traces (`SERIAL_BAUD` in `makefile.config`)
+pub const FW_DBG_BAUD: u32 = 9600;
+/// Max length of a token, longer tokens are flushed as `Unknown`
+const MAX_TOKEN_LEN: usize = 64;
+
+/// Events traced by the firmware
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FwEvent {
+    /// Boot banner `[BOOT:version]`
+    Boot { version: u32 },
+    /// State machine transition `{from->to}`
+    Transition { from: u8, to: u8 },
+    /// Command executed `{c}`
+    Command { cmd: char },
+    /// Watchdog started `[W:limit]`
+    Watchdog { limit: u32 },
+    /// Watchdog countdown `{Wlimit,counter}` (commented out in `wdg_check` of current firmware)
+    WatchdogCount { limit: u32, count: u32 },
+    /// I2C slave (re)started `[2CS]`
+    I2cStart,
+    /// I2C recovery `[I2CR E:xx 1:xx 2:xx 3:xx]`
+    I2cRecover { error: u8, sr1: u8, sr2: u8, sr3: u8 },
+    /// Clicks transferred to registers `[TR]`
+    ClickTransfer,
+    /// Power button hold detected `[H:ms]`
+    Hold { ms: u32 },
+    /// Button pressed `D:button,ms`
+    ButtonDown { button: u8, ms: u32 },
+    /// Button released `U:button,ms`
+    ButtonUp { button: u8, ms: u32 },
+    /// Beep started `BEEP:csr`
+    Beep { csr: u8 },
+    /// One second tick `.`
+    Tick,
+    /// Anything else
+    Unknown { raw: String },
+}
+
+/// Name of the firmware states (`VpiStatus_t` in `main.c`)
+pub fn fw_state_name(s: u8) -> &'static str {
+    match s {
+        0 => "BOOTING",
+        1 => "RUNNING",
+        2 => "SHUTDOWN",
+        3 => "WDOG",
+        4 => "OFF",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Name of the commands (`VPI_CMD_*` in `vpi_regs.h`)
+pub fn fw_cmd_name(c: char) -> &'static str {
+    match c {
+        'A' => "activate config",
+        'B' => "boot",
+        'I' => "init",
+        'F' => "feed",
+        'H' => "hard shutdown",
+        'S' => "shutdown",
+        'C' => "clear",
+        'N' => "fan",
+        'L' => "led",
+        'Z' => "beep",
+        '1' => "output set",
+        '0' => "output clear",
+        'T' => "reset",
+        'W' => "watchdog set",
+        'V' => "watchdog reset",
+        'E' => "wake enable",
+        'D' => "wake disable",
+        'e' => "irq wake enable",
+        'd' => "irq wake disable",
+        _ => "unknown",
+    }
+}
+
+fn button_name(b: u8) -> &'static str {
+    match b {
+        0 => "PWR",
+        1 => "AUX",
+        _ => "?",
+    }
+}
+
+impl fmt::Display for FwEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Boot { version } => write!(f, "Boot firmware v{}", version),
+            Self::Transition { from, to } => write!(
+                f,
+                "State {} -> {}",
+                fw_state_name(*from),
+                fw_state_name(*to)
+            ),
+            Self::Command { cmd } => write!(f, "Command '{}' ({})", cmd, fw_cmd_name(*cmd)),
+            Self::Watchdog { limit } => write!(f, "Watchdog limit {} s", limit),
+            Self::WatchdogCount { limit, count } => {
+                write!(f, "Watchdog count {} of {} s", count, limit)
+            }
+            Self::I2cStart => write!(f, "I2C slave started"),
+            Self::I2cRecover {
+                error,
+                sr1,
+                sr2,
+                sr3,
+            } => write!(
+                f,
+                "I2C recover [error:0x{:02X} SR1:0x{:02X} SR2:0x{:02X} SR3:0x{:02X}]",
+                error, sr1, sr2, sr3
+            ),
+            Self::ClickTransfer => write!(f, "Clicks transferred"),
+            Self::Hold { ms } => write!(f, "Power button hold [{} ms]", ms),
+            Self::ButtonDown { button, ms } => {
+                write!(f, "Button {} down at {} ms", button_name(*button), ms)
+            }
+            Self::ButtonUp { button, ms } => {
+                write!(f, "Button {} up at {} ms", button_name(*button), ms)
+            }
+            Self::Beep { csr } => write!(f, "Beep [CSR:0x{:02X}]", csr),
+            Self::Tick => write!(f, "."),
+            Self::Unknown { raw } => write!(f, "Unknown '{}'", raw),
+        }
+    }
+}
+
+/// Parse a list of decimal numbers separated by `sep`
+fn numbers(s: &str, sep: char) -> Option<Vec<u32>> {
+    s.split(sep).map(|n| n.trim().parse::<u32>().ok()).collect()
+}
+
+/// Parse the inner text of a `[...]` token
+fn parse_bracket(inner: &str) -> Option<FwEvent> {
+    if inner == "TR" {
+        return Some(FwEvent::ClickTransfer);
+    }
+    if inner == "2CS" {
+        return Some(FwEvent::I2cStart);
+    }
+    if let Some(v) = inner.strip_prefix("BOOT:") {
+        return v.parse().ok().map(|version| FwEvent::Boot { version });
+    }
+    if let Some(v) = inner.strip_prefix("W:") {
+        return v.parse().ok().map(|limit| FwEvent::Watchdog { limit });
+    }
+    if let Some(v) = inner.strip_prefix("H:") {
+        return v.parse().ok().map(|ms| FwEvent::Hold { ms });
+    }
+    if let Some(v) = inner.strip_prefix("I2CR ") {
+        let mut regs = [0u8; 4];
+        let fields: Vec<&str> = v.split_whitespace().collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        for (i, fi) in fields.iter().enumerate() {
+            let hex = fi.split_once(':')?.1;
+            regs[i] = u8::from_str_radix(hex, 16).ok()?;
+        }
+        return Some(FwEvent::I2cRecover {
+            error: regs[0],
+            sr1: regs[1],
+            sr2: regs[2],
+            sr3: regs[3],
+        });
+    }
+    None
+}
+
+/// Parse the inner text of a `{...}` token
+fn parse_brace(inner: &str) -> Option<FwEvent> {
+    if let Some(pos) = inner.find("->") {
+        let from = inner[..pos].parse().ok()?;
+        let to = inner[pos + 2..].parse().ok()?;
+        return Some(FwEvent::Transition { from, to });
+    }
+    if let Some(v) = inner.strip_prefix('W').filter(|v| !v.is_empty()) {
+        let n = numbers(v, ',')?;
+        if n.len() != 2 {
+            return None;
+        }
+        return Some(FwEvent::WatchdogCount {
+            limit: n[0],
+            count: n[1],
+        });
+    }
+    let mut chars = inner.chars();
+    match (chars.next(), chars.next()) {
+        (Some(cmd), None) => Some(FwEvent::Command { cmd }),
+        _ => None,
+    }
+}
+
+/// Parse an unbracketed `PREFIX:values` token
+fn parse_free(token: &str) -> Option<FwEvent> {
+    if let Some(v) = token.strip_prefix("BEEP:") {
+        return u8::from_str_radix(v, 16).ok().map(|csr| FwEvent::Beep { csr });
+    }
+    let down = token.starts_with("D:");
+    if down || token.starts_with("U:") {
+        let n = numbers(&token[2..], ',')?;
+        if n.len() != 2 || n[0] > 1 {
+            return None;
+        }
+        let (button, ms) = (n[0] as u8, n[1]);
+        return Some(if down {
+            FwEvent::ButtonDown { button, ms }
+        } else {
+            FwEvent::ButtonUp { button, ms }
+        });
+    }
+    None
+}
+
+/// Decoder state
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Inside a token closed by the char
+    Closed(char),
+    /// Inside a free token, value chars are decimal or hex (BEEP)
+    Free,
+}
+
+/// Incremental decoder of the debug stream.
+/// Feed bytes as they arrive; complete tokens produce events.
+#[derive(Debug)]
+pub struct FwDecoder {
+    state: State,
+    token: String,
+    /// Seconds since last boot banner (counted from ticks)
+    uptime: u32,
+}
+
+impl Default for FwDecoder {
+    fn default() -> Self {
+        FwDecoder {
+            state: State::Idle,
+            token: String::new(),
+            uptime: 0,
+        }
+    }
+}
+
+impl FwDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Board seconds since last boot as counted by the ticks
+    pub fn uptime(&self) -> u32 {
+        self.uptime
+    }
+    /// Flush current token as unknown
+    fn flush(&mut self) -> Option<FwEvent> {
+        self.state = State::Idle;
+        if self.token.is_empty() {
+            None
+        } else {
+            let raw = std::mem::take(&mut self.token);
+            Some(parse_free(&raw).unwrap_or(FwEvent::Unknown { raw }))
+        }
+    }
+    /// Account events affecting decoder state.
+    fn account(&mut self, ev: FwEvent) -> FwEvent {
+        match ev {
+            FwEvent::Boot { .. } => self.uptime = 0,
+            FwEvent::Tick => self.uptime += 1,
+            _ => {}
+        }
+        ev
+    }
+    /// Is `c` part of the value of current free token
+    fn free_char(&self, c: char) -> bool {
+        match self.token.find(':') {
+            None => c.is_ascii_uppercase() || c == ':',
+            Some(p) => {
+                let value_len = self.token.len() - p - 1;
+                if self.token.starts_with("BEEP") {
+                    c.is_ascii_hexdigit() && value_len < 2
+                } else {
+                    c.is_ascii_digit() || c == ','
+                }
+            }
+        }
+    }
+    /// Feed one byte. Returns up to two events (the byte may end a free token and
+    /// produce a tick or start a new one).
+    pub fn feed(&mut self, b: u8) -> Vec<FwEvent> {
+        let c = b as char;
+        let mut out = vec![];
+        if let State::Closed(close) = self.state {
+            if c == close {
+                let raw = std::mem::take(&mut self.token);
+                self.state = State::Idle;
+                let ev = if close == ']' {
+                    parse_bracket(&raw)
+                } else {
+                    parse_brace(&raw)
+                };
+                let ev = ev.unwrap_or(FwEvent::Unknown {
+                    raw: format!("{}{}{}", if close == ']' { '[' } else { '{' }, raw, close),
+                });
+                out.push(self.account(ev));
+            } else if self.token.len() >= MAX_TOKEN_LEN || c == '\n' {
+                if let Some(ev) = self.flush() {
+                    out.push(ev);
+                }
+            } else if c != '\r' {
+                self.token.push(c);
+            }
+            return out;
+        }
+        if self.state == State::Free {
+            if self.free_char(c) && self.token.len() < MAX_TOKEN_LEN {
+                self.token.push(c);
+                return out;
+            }
+            if let Some(ev) = self.flush() {
+                out.push(ev);
+            }
+        }
+        match c {
+            '[' => self.state = State::Closed(']'),
+            '{' => self.state = State::Closed('}'),
+            '.' => out.push(self.account(FwEvent::Tick)),
+            c if c.is_ascii_uppercase() => {
+                self.state = State::Free;
+                self.token.push(c);
+            }
+            c if c.is_ascii_whitespace() || c == '\0' => {}
+            c => out.push(FwEvent::Unknown { raw: c.to_string() }),
+        }
+        out
+    }
+}
+
+/// Reader of firmware events over any byte source (tty, pty, file,...)
+pub struct FwReader<R: Read> {
+    src: R,
+    decoder: FwDecoder,
+    /// Decoded events with the uptime at decode time
+    pending: Vec<(u32, FwEvent)>,
+    uptime: u32,
+    buff: [u8; 64],
+}
+
+impl<R: Read> FwReader<R> {
+    pub fn new(src: R) -> Self {
+        FwReader {
+            src,
+            decoder: FwDecoder::new(),
+            pending: vec![],
+            uptime: 0,
+            buff: [0u8; 64],
+        }
+    }
+    /// Board seconds since last boot (as counted by the ticks) of the last event returned
+    pub fn uptime(&self) -> u32 {
+        self.uptime
+    }
+}
+
+impl<R: Read> Iterator for FwReader<R> {
+    type Item = std::io::Result<FwEvent>;
+    /// Blocks until the next event is decoded. Ends on EOF.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if !self.pending.is_empty() {
+                let (uptime, ev) = self.pending.remove(0);
+                self.uptime = uptime;
+                return Some(Ok(ev));
+            }
+            match self.src.read(&mut self.buff) {
+                Ok(0) => return None,
+                Ok(n) => {
+                    for i in 0..n {
+                        for ev in self.decoder.feed(self.buff[i]) {
+                            self.pending.push((self.decoder.uptime(), ev));
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Map a numeric baud rate to termios
+fn baud_rate(baud: u32) -> std::io::Result<BaudRate> {
+    match baud {
+        1200 => Ok(BaudRate::B1200),
+        2400 => Ok(BaudRate::B2400),
+        4800 => Ok(BaudRate::B4800),
+        9600 => Ok(BaudRate::B9600),
+        19200 => Ok(BaudRate::B19200),
+        38400 => Ok(BaudRate::B38400),
+        57600 => Ok(BaudRate::B57600),
+        115200 => Ok(BaudRate::B115200),
+        230400 => Ok(BaudRate::B230400),
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unsupported baud rate {}", baud),
+        )),
+    }
+}
+
+/// Open a serial device (or pty) in raw mode at `baud` 8N1
+pub fn open_tty(path: &Path, baud: u32) -> std::io::Result<File> {
+    let speed = baud_rate(baud)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    let mut tio = termios::tcgetattr(fd)?;
+    termios::cfmakeraw(&mut tio);
+    termios::cfsetspeed(&mut tio, speed)?;
+    termios::tcsetattr(fd, SetArg::TCSANOW, &tio)?;
+    Ok(file)
+}
+
+/// Open a tty and return the event reader
+pub fn open(path: &Path, baud: u32) -> std::io::Result<FwReader<File>> {
+    Ok(FwReader::new(open_tty(path, baud)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn decode(s: &str) -> Vec<FwEvent> {
+        let mut dec = FwDecoder::new();
+        s.bytes().flat_map(|b| dec.feed(b)).collect()
+    }
+
+    #[test]
+    fn all_formats() {
+        let evs = decode(
+            "[BOOT:3]{0->1}{F}[W:30]{W30,12}[2CS][I2CR E:04 1:00 2:02 3:80][TR][H:3200]D:0,1500U:1,1750BEEP:0A.",
+        );
+        assert_eq!(
+            evs,
+            vec![
+                FwEvent::Boot { version: 3 },
+                FwEvent::Transition { from: 0, to: 1 },
+                FwEvent::Command { cmd: 'F' },
+                FwEvent::Watchdog { limit: 30 },
+                FwEvent::WatchdogCount {
+                    limit: 30,
+                    count: 12
+                },
+                FwEvent::I2cStart,
+                FwEvent::I2cRecover {
+                    error: 0x04,
+                    sr1: 0x00,
+                    sr2: 0x02,
+                    sr3: 0x80
+                },
+                FwEvent::ClickTransfer,
+                FwEvent::Hold { ms: 3200 },
+                FwEvent::ButtonDown {
+                    button: 0,
+                    ms: 1500
+                },
+                FwEvent::ButtonUp {
+                    button: 1,
+                    ms: 1750
+                },
+                FwEvent::Beep { csr: 0x0A },
+                FwEvent::Tick,
+            ]
+        );
+    }
+
+    #[test]
+    fn watchdog_command_not_count() {
+        assert_eq!(decode("{W}"), vec![FwEvent::Command { cmd: 'W' }]);
+        assert_eq!(
+            decode("{W1}"),
+            vec![FwEvent::Unknown {
+                raw: "{W1}".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn split_tokens() {
+        let mut dec = FwDecoder::new();
+        let mut evs = vec![];
+        for chunk in ["[BO", "OT:", "2]", "{1", "->", "3}D:", "0,", "99", ".."].iter() {
+            for b in chunk.bytes() {
+                evs.extend(dec.feed(b));
+            }
+        }
+        assert_eq!(
+            evs,
+            vec![
+                FwEvent::Boot { version: 2 },
+                FwEvent::Transition { from: 1, to: 3 },
+                FwEvent::ButtonDown { button: 0, ms: 99 },
+                FwEvent::Tick,
+                FwEvent::Tick,
+            ]
+        );
+        assert_eq!(dec.uptime(), 2);
+        dec.feed(b'[');
+        "BOOT:2]".bytes().for_each(|b| {
+            dec.feed(b);
+        });
+        assert_eq!(dec.uptime(), 0);
+    }
+
+    #[test]
+    fn overlong_tokens() {
+        let long = "X".repeat(MAX_TOKEN_LEN * 2);
+        let evs = decode(&format!("[{}]{{0->1}}", long));
+        assert!(matches!(evs[0], FwEvent::Unknown { .. }));
+        assert_eq!(evs.last(), Some(&FwEvent::Transition { from: 0, to: 1 }));
+        assert!(evs
+            .iter()
+            .all(|e| !matches!(e, FwEvent::Unknown { raw } if raw.len() > MAX_TOKEN_LEN + 2)));
+        let evs = decode(&format!("{}.", long));
+        assert_eq!(evs.last(), Some(&FwEvent::Tick));
+        assert!(evs.len() > 2);
+    }
+
+    #[test]
+    fn newline_ends_token() {
+        assert_eq!(
+            decode("[BOOT\n[TR]\r\n"),
+            vec![
+                FwEvent::Unknown {
+                    raw: "BOOT".to_string()
+                },
+                FwEvent::ClickTransfer,
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(
+            decode("#"),
+            vec![FwEvent::Unknown {
+                raw: "#".to_string()
+            }]
+        );
+        assert_eq!(
+            decode("[BOOT:x]"),
+            vec![FwEvent::Unknown {
+                raw: "[BOOT:x]".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn pty_reader() {
+        use nix::fcntl::OFlag;
+        use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+        let mut master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let slave = ptsname_r(&master).unwrap();
+        let reader = open(Path::new(&slave), FW_DBG_BAUD).unwrap();
+        master.write_all(b"[BOOT:3]..{1->2}").unwrap();
+        let evs: Vec<FwEvent> = reader.take(4).map(|e| e.unwrap()).collect();
+        assert_eq!(
+            evs,
+            vec![
+                FwEvent::Boot { version: 3 },
+                FwEvent::Tick,
+                FwEvent::Tick,
+                FwEvent::Transition { from: 1, to: 2 },
+            ]
+        );
+    }
+}
@@ -9,11 +9,13 @@
 extern crate i2cdev;
 #[macro_use]
 extern crate memoffset;
+#[macro_use]
+extern crate log;
 
 //Core imports
 use i2cdev::core::I2CDevice;
 use i2cdev::linux::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::io::ErrorKind;
 use std::mem;
 use std::path::PathBuf;
@@ -24,6 +26,7 @@ use cmd::{VpiCmd, VpiCmdOutput};
 
 // Define
 pub mod cmd;
+pub mod fwdbg;
 pub mod uploader;
 
 
@@ -69,16 +72,16 @@ impl VpiTimes {
             .map(|e| e.parse::<i32>().unwrap_or(-1))
             .collect();
         let mut r = VpiTimes::default();
-        if p.len() >= 1 && p[0] > 20 && p[0] <= u16::max as i32 {
+        if !p.is_empty() && p[0] > 20 && p[0] <= u16::MAX as i32 {
             r.short_tm = p[0] as u16
         }
-        if p.len() >= 2 && p[1] > 100 && p[1] <= u16::max as i32 {
+        if p.len() >= 2 && p[1] > 100 && p[1] <= u16::MAX as i32 {
             r.space_tm = p[1] as u16
         }
-        if p.len() >= 3 && p[2] > 0 && p[2] <= u8::max as i32 {
+        if p.len() >= 3 && p[2] > 0 && p[2] <= u8::MAX as i32 {
             r.hold_tm = p[2] as u8
         }
-        if p.len() >= 4 && p[3] > 0 && p[3] <= u8::max as i32 {
+        if p.len() >= 4 && p[3] > 0 && p[3] <= u8::MAX as i32 {
             r.grace_tm = p[3] as u8
         }
         r
@@ -127,7 +130,7 @@ impl VpiBuzz {
 }
 
 /// Status in more usable form
-#[derive(Debug, Default, Copy, Clone, Serialize)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct VpiStatus {
     pub has_click: bool,
     pub has_rpm: bool,
@@ -265,17 +268,110 @@ const VPI_HAS_OUT_FLA: u8 = 4;
 /// Default I2C Address ChangeMe to chose other address
 pub const VPI_I2C_ADDR: u16 = 0x33;
 
+/// Register map of the firmware header `vpi_regs.h` (generated by build.rs)
+#[allow(dead_code)]
+mod regs_h {
+    include!(concat!(env!("OUT_DIR"), "/vpi_regs.rs"));
+}
+
+/// Compile time check of an offset or constant against the firmware header
+#[cfg(vpi_regs_h)]
+macro_rules! check_regs_h {
+    ($rust:expr, $c:ident) => {
+        assert!(
+            $rust as u32 == regs_h::$c as u32,
+            concat!(stringify!($c), " does not match firmware vpi_regs.h")
+        );
+    };
+}
+
+/// `VpiRegs` layout and constants must match the firmware. Field sizes are
+/// implied by the offsets of consecutive fields and the total size.
+#[cfg(vpi_regs_h)]
+const _: () = {
+    check_regs_h!(mem::size_of::<VpiRegs>(), VPIREGS_SIZE);
+    // RO section
+    check_regs_h!(offset_of!(VpiRegs, id), OFFSET_ID);
+    check_regs_h!(offset_of!(VpiRegs, v), OFFSET_V);
+    check_regs_h!(offset_of!(VpiRegs, status), OFFSET_STATUS);
+    check_regs_h!(offset_of!(VpiRegs, flags), OFFSET_FLAGS);
+    check_regs_h!(offset_of!(VpiRegs, crc), OFFSET_CRC);
+    check_regs_h!(offset_of!(VpiRegs, buts), OFFSET_BUTS);
+    check_regs_h!(mem::size_of::<[[u8; 2]; 2]>(), SIZE_BUTS);
+    check_regs_h!(offset_of!(VpiRegs, rpm), OFFSET_RPM);
+    check_regs_h!(offset_of!(VpiRegs, err_count), OFFSET_ERR_COUNT);
+    check_regs_h!(offset_of!(VpiRegs, uuid), OFFSET_UUID);
+    check_regs_h!(mem::size_of::<[u8; 12]>(), SIZE_UUID);
+    // RW section
+    check_regs_h!(offset_of!(VpiRegs, pwm_freq), OFFSET_PWM_FREQ);
+    check_regs_h!(offset_of!(VpiRegs, rev_divisor), OFFSET_REV_DIVISOR);
+    check_regs_h!(offset_of!(VpiRegs, wdg), OFFSET_WDG);
+    check_regs_h!(offset_of!(VpiRegs, wake), OFFSET_WAKE);
+    check_regs_h!(offset_of!(VpiRegs, times) + offset_of!(VpiTimes, short_tm), OFFSET_SHORT_TM);
+    check_regs_h!(offset_of!(VpiRegs, times) + offset_of!(VpiTimes, space_tm), OFFSET_SPACE_TM);
+    check_regs_h!(offset_of!(VpiRegs, times) + offset_of!(VpiTimes, hold_tm), OFFSET_HOLD_TM);
+    check_regs_h!(offset_of!(VpiRegs, times) + offset_of!(VpiTimes, grace_tm), OFFSET_GRACE_TM);
+    check_regs_h!(offset_of!(VpiRegs, led) + offset_of!(VpiLed, led_mode), OFFSET_LED_MODE);
+    check_regs_h!(offset_of!(VpiRegs, led) + offset_of!(VpiLed, led_val), OFFSET_LED_VAL);
+    check_regs_h!(offset_of!(VpiRegs, buzz) + offset_of!(VpiBuzz, buzz_freq), OFFSET_BUZZ_FREQ);
+    check_regs_h!(offset_of!(VpiRegs, buzz) + offset_of!(VpiBuzz, buzz_b_tm), OFFSET_BUZZ_B_TM);
+    check_regs_h!(offset_of!(VpiRegs, buzz) + offset_of!(VpiBuzz, buzz_p_tm), OFFSET_BUZZ_P_TM);
+    check_regs_h!(offset_of!(VpiRegs, buzz) + offset_of!(VpiBuzz, buzz_count), OFFSET_BUZZ_COUNT);
+    check_regs_h!(offset_of!(VpiRegs, fan_val), OFFSET_FAN_VAL);
+    check_regs_h!(offset_of!(VpiRegs, cmd), OFFSET_CMD);
+    check_regs_h!(offset_of!(VpiRegs, icmd), OFFSET_ICMD);
+    // Commands
+    check_regs_h!(VPI_CMD_NOP, VPI_CMD_NOP);
+    check_regs_h!(VPI_CMD_ACT, VPI_CMD_ACT);
+    check_regs_h!(VPI_CMD_BOOT, VPI_CMD_BOOT);
+    check_regs_h!(VPI_CMD_INIT, VPI_CMD_INIT);
+    check_regs_h!(VPI_CMD_FEED, VPI_CMD_FEED);
+    check_regs_h!(VPI_CMD_HARD, VPI_CMD_HARD);
+    check_regs_h!(VPI_CMD_SHUT, VPI_CMD_SHUT);
+    check_regs_h!(VPI_CMD_CLEAR, VPI_CMD_CLEAR);
+    check_regs_h!(VPI_CMD_FAN, VPI_CMD_FAN);
+    check_regs_h!(VPI_CMD_LED, VPI_CMD_LED);
+    check_regs_h!(VPI_CMD_BEEP, VPI_CMD_BEEP);
+    check_regs_h!(VPI_CMD_OUTSET, VPI_CMD_OUTSET);
+    check_regs_h!(VPI_CMD_OUTCL, VPI_CMD_OUTCL);
+    check_regs_h!(VPI_CMD_RESET, VPI_CMD_RESET);
+    check_regs_h!(VPI_CMD_WDGSET, VPI_CMD_WDGSET);
+    check_regs_h!(VPI_CMD_WDGRST, VPI_CMD_WDGRST);
+    check_regs_h!(VPI_CMD_WEN, VPI_CMD_WEN);
+    check_regs_h!(VPI_CMD_WDI, VPI_CMD_WDI);
+    check_regs_h!(VPI_CMD_IEN, VPI_CMD_IEN);
+    check_regs_h!(VPI_CMD_IDI, VPI_CMD_IDI);
+    // Buttons
+    check_regs_h!(BUT_PWR, BUT_PWR);
+    check_regs_h!(BUT_AUX, BUT_AUX);
+    check_regs_h!(BUT_SHORT, BUT_SHORT);
+    check_regs_h!(BUT_LONG, BUT_LONG);
+    // Flags
+    check_regs_h!(VPI_HAS_CLICK, VPI_CLICK_FLAG);
+    check_regs_h!(VPI_HAS_RPM, VPI_RPM_FLAG);
+    check_regs_h!(VPI_HAS_ERROR, VPI_ERROR_FLAG);
+    check_regs_h!(VPI_IS_RUNNING, VPI_RUNING_FLAG);
+    check_regs_h!(VPI_HAS_WDG, VPI_WDG_FLAG);
+    check_regs_h!(VPI_HAS_IRQ, VPI_IRQ_FLAG);
+    check_regs_h!(VPI_HAS_WAKEENI, VPI_WAKEENI_FLAG);
+    check_regs_h!(VPI_HAS_WAKEEN, VPI_WAKEEN_FLAG);
+    check_regs_h!(VPI_HAS_OUT_FLA, VPI_OUT_FLAG);
+    // Device
+    check_regs_h!(VPI_I2C_ADDR, VPI_I2C_ADDR);
+    check_regs_h!(VPI_DEVICE_MAGIK, VPI_DEVICE_MAGIK);
+};
+
 /// Stats for Vpi
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct VpiStats {
     pub retries: u32,
     pub recovers: u32,
     pub i2c_errors: u32,
     pub status_checks: u64,
     pub crc_errors: u32,
-    #[serde(skip)]
+    #[serde(skip, default = "time::Instant::now")]
     last_read: time::Instant,
-    #[serde(skip)]
+    #[serde(skip, default = "time::Instant::now")]
     last_write: time::Instant,
 }
 
@@ -470,10 +566,13 @@ impl Vpi {
         dev.write(&addr)?;
         Vpi::sleep_ms(1); // give some time to set the register in the device safetly
         dev.read(buff)?;
-        if self.debug {
+        if self.debug || log_enabled!(target: "vpi::i2c", log::Level::Trace) {
             let u: Vec<String> = buff.into_iter().map(|b| format!("{:02X}", b)).collect();
             let c = u.join(" ");
-            println!("I2C-RD reg:0x{:02X} len:{}, values:{}", reg, buff.len(), c);
+            if self.debug {
+                println!("I2C-RD reg:0x{:02X} len:{}, values:{}", reg, buff.len(), c);
+            }
+            trace!(target: "vpi::i2c", "I2C-RD reg:0x{:02X} len:{}, values:{}", reg, buff.len(), c);
         }
         self.stats.last_read = time::Instant::now();
         Ok(())
@@ -559,15 +658,18 @@ impl Vpi {
         let as_buff = unsafe { std::slice::from_raw_parts(ptr.add(r), len as usize) };
         total.extend_from_slice(as_buff);
         dev.write(total.as_slice())?;
-        if self.debug {
+        if self.debug || log_enabled!(target: "vpi::i2c", log::Level::Trace) {
             let u: Vec<String> = as_buff.into_iter().map(|b| format!("{:02X}", b)).collect();
             let c = u.join(" ");
-            println!(
-                "I2C-WR reg:0x{:02X} len:{} values:{}",
-                reg,
-                as_buff.len(),
-                c
-            );
+            if self.debug {
+                println!(
+                    "I2C-WR reg:0x{:02X} len:{} values:{}",
+                    reg,
+                    as_buff.len(),
+                    c
+                );
+            }
+            trace!(target: "vpi::i2c", "I2C-WR reg:0x{:02X} len:{} values:{}", reg, as_buff.len(), c);
         }
         self.stats.last_write = time::Instant::now();
         Ok(())
@@ -1041,16 +1143,18 @@ impl Vpi {
             }
             VpiCmd::Timing(tim) => {
                 self.timings(tim).config()?;
-                unsafe {
-                    Ok(VpiCmdOutput::t_or_j(
-                        format!(
-                            "Button timming set to [short={}ms,space={}ms,hold={}s,grace={}s]",
-                            tim.short_tm, tim.space_tm, tim.hold_tm, tim.grace_tm
-                        )
-                        .as_str(),
-                        js,
-                    ))
-                }
+                // Copy packed fields before formatting (no references to unaligned fields)
+                Ok(VpiCmdOutput::t_or_j(
+                    format!(
+                        "Button timming set to [short={}ms,space={}ms,hold={}s,grace={}s]",
+                        { tim.short_tm },
+                        { tim.space_tm },
+                        { tim.hold_tm },
+                        { tim.grace_tm }
+                    )
+                    .as_str(),
+                    js,
+                ))
             }
             VpiCmd::Divisor(div) => {
                 self.rev_divisor(*div).config()?;
@@ -118,6 +118,62 @@ pub fn upload(
     file: &PathBuf,
     rst_pin: u16,
 ) -> Result<(), LinuxI2CError> {
+    let mut pb: Option<ProgressBar<std::io::Stdout>> = None;
+    let res = upload_with_progress(addr, dev_path, file, rst_pin, |step| match step {
+        UploadStep::Read { blocks, crc } => {
+            println!("Firmware readed. blocks={},CRC={:x}", blocks, crc)
+        }
+        UploadStep::Request => print!("Sending upload request..."),
+        UploadStep::Response => print!("R."),
+        UploadStep::Block { done: 0, total } => {
+            println!("Ok! Starting upload...");
+            pb = Some(ProgressBar::new(total as u64));
+        }
+        UploadStep::Block { .. } => {
+            if let Some(p) = pb.as_mut() {
+                p.inc();
+            }
+        }
+        UploadStep::Confirm => print!("Confirming upload..."),
+    });
+    if res.is_ok() {
+        println!("Ok!");
+    }
+    res
+}
+
+/// Steps of the firmware upload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadStep {
+    /// Firmware file read
+    Read { blocks: u8, crc: u8 },
+    /// Sending the activation request to the bootloader
+    Request,
+    /// Waiting the bootloader response
+    Response,
+    /// Blocks uploaded. First with `done` 0 once the bootloader accepted the upload.
+    Block { done: u32, total: u32 },
+    /// Waiting the confirmation of the firmware
+    Confirm,
+}
+
+/// Uploads the firmeware using i2c reporting the progress
+/// # Arguments
+/// `addr` - i2c address of the i2c board
+/// `dev_path` - path to i2c devive /dev/i2c-XX
+/// `file` - file path of the firmware
+/// `rst_pin` - pin used for reset the stm8s chip (rest will be high level)
+/// `progress` - called on each step of the upload
+pub fn upload_with_progress<F>(
+    addr: u8,
+    dev_path: &PathBuf,
+    file: &PathBuf,
+    rst_pin: u16,
+    mut progress: F,
+) -> Result<(), LinuxI2CError>
+where
+    F: FnMut(UploadStep),
+{
     let mut dev = LinuxI2CDevice::new(dev_path, addr as u16)?;
     let crc: u8;
     let blocks: u8;
@@ -130,16 +186,16 @@ pub fn upload(
             }
             Err(e) => return Err(LinuxI2CError::Io(e)),
         }
-        println!("Firmware readed. blocks={},CRC={:x}", blocks, crc);
+        progress(UploadStep::Read { blocks, crc });
         // Initiate bootloader sequence
         reset(rst_pin)?;
         let req: [u8; 7] = [0xde, 0xad, 0xbe, 0xef, blocks, crc, crc]; // Activation msg
         let mut resp: [u8; 2] = NACK;
-        print!("Sending upload request...");
+        progress(UploadStep::Request);
         pause();
         dev.write(&req)?;
         pause();
-        print!("R.");
+        progress(UploadStep::Response);
         dev.read(&mut resp)?;
         if resp != ACK {
             return Err(LinuxI2CError::Io(std::io::Error::new(
@@ -147,20 +203,25 @@ pub fn upload(
                 "Bootloader activation response:NACK",
             )));
         }
-        println!("Ok! Starting upload...");
+        progress(UploadStep::Block {
+            done: 0,
+            total: blocks as u32,
+        });
         // Upload chucks
-        let mut pb = ProgressBar::new(blocks as u64);
         let mut f = OpenOptions::new().read(true).open(file)?;
-        for _i in 0..blocks {
+        for i in 0..blocks {
             let mut chunk: [u8; BLOCK_SIZE] = [0xFF; BLOCK_SIZE];
-            f.read(&mut chunk)?;
+            let _len = f.read(&mut chunk)?; // Last block padded with 0xFF
             dev.write(&chunk)?;
-            pb.inc();
+            progress(UploadStep::Block {
+                done: i as u32 + 1,
+                total: blocks as u32,
+            });
             pause();
         }
         // ACK confirmation of the firmware
         resp = NACK;
-        print!("Confirming upload...");
+        progress(UploadStep::Confirm);
         pause();
         dev.read(&mut resp)?;
         if resp != ACK {
@@ -169,7 +230,6 @@ pub fn upload(
                 "Bootloader activation response:NACK",
             )))
         } else {
-            println!("Ok!");
             Ok(())
         }
     } else {
@@ -0,0 +1,390 @@
+//! Client library for the vpid unix socket protocol.
+//! vpid accepts text commands (`status`, `fan 100`, `setkey k v`,...) terminated by
+//! a new line and answers with a JSON envelope `{"result":bool,"data":...}`.
+//! This crate hides the protocol behind typed methods:
+//!
+//! ```no_run
+//! let client = vpid_client::VpidClient::new("/var/run/vpid.sock");
+//! let status = client.status().unwrap();
+//! println!("Fan speed {} rpm", status.rpm);
+//! ```
+//!
+//! A [`VpidSession`] keeps the connection open for many requests, useful to poll vpid.
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+pub use vpi::{VpiStats, VpiStatus};
+
+/// Default socket of vpid
+pub const VPID_SOCKET: &str = "/var/run/vpid.sock";
+/// Default timeout for a request. vpid waits up to 2 s for the board.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("Couldn't connect to vpid socket {}: {}", sock.display(), source))]
+    Connect {
+        sock: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("vpid did not responded or timeout occurred: {}", source))]
+    Io { source: std::io::Error },
+    #[snafu(display("Invalid JSON response '{}': {}", response, source))]
+    Json {
+        response: String,
+        source: serde_json::error::Error,
+    },
+    #[snafu(display("Unexpected response data {}: {}", data, source))]
+    Data {
+        data: Value,
+        source: serde_json::error::Error,
+    },
+    #[snafu(display("Response id {} does not match request id {}", got, expected))]
+    Id { expected: u64, got: Value },
+    #[snafu(display("Command '{}' failed: {}", cmd, data))]
+    Command { cmd: String, data: Value },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Client of the vpid socket
+#[derive(Debug, Clone)]
+pub struct VpidClient {
+    sock: PathBuf,
+    timeout: Duration,
+}
+
+impl VpidClient {
+    /// New client for the socket at `sock`. No connection is done here.
+    pub fn new<P: AsRef<Path>>(sock: P) -> Self {
+        VpidClient {
+            sock: sock.as_ref().to_path_buf(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+    /// Set the read & write timeout of the requests
+    pub fn timeout(mut self, t: Duration) -> Self {
+        self.timeout = t;
+        self
+    }
+    /// Socket path of the client
+    pub fn socket(&self) -> &Path {
+        &self.sock
+    }
+    /// Open a persistent session
+    pub fn session(&self) -> Result<VpidSession> {
+        let stream = UnixStream::connect(&self.sock).context(Connect { sock: &self.sock })?;
+        stream.set_read_timeout(Some(self.timeout)).context(Io)?;
+        stream.set_write_timeout(Some(self.timeout)).context(Io)?;
+        let reader = BufReader::new(stream.try_clone().context(Io)?);
+        Ok(VpidSession {
+            stream,
+            reader,
+            next_id: 1,
+        })
+    }
+    /// Subscribe to the events of vpid. `events` filters by name (`button`, `rpm`,...),
+    /// all events if empty. The subscription waits for events without timeout.
+    pub fn subscribe(&self, events: &[&str]) -> Result<Subscription> {
+        let mut session = self.session()?;
+        let cmd = format!("subscribe {}", events.join(" "));
+        session.command(&cmd)?;
+        session.stream.set_read_timeout(None).context(Io)?;
+        Ok(Subscription { session })
+    }
+    /// Send a raw command line and return the JSON envelope as sent by vpid.
+    /// A `"result":false` response is not considered an error here.
+    pub fn request(&self, line: &str) -> Result<Value> {
+        let mut stream = UnixStream::connect(&self.sock).context(Connect { sock: &self.sock })?;
+        stream.set_read_timeout(Some(self.timeout)).context(Io)?;
+        stream.set_write_timeout(Some(self.timeout)).context(Io)?;
+        stream
+            .write_all(format!("{}\n", line.trim()).as_bytes())
+            .context(Io)?;
+        stream.flush().context(Io)?;
+        let mut resp = String::new();
+        BufReader::new(&stream).read_line(&mut resp).context(Io)?;
+        serde_json::from_str(resp.trim()).context(Json { response: resp })
+    }
+    /// Send a command and return the `data` of the response.
+    /// `"result":false` responses are mapped to `Error::Command`.
+    pub fn command(&self, cmd: &str) -> Result<Value> {
+        response_data(cmd, self.request(cmd)?)
+    }
+    /// Send a command and deserialize the `data` of the response
+    fn typed<T: DeserializeOwned>(&self, cmd: &str) -> Result<T> {
+        let data = self.command(cmd)?;
+        serde_json::from_value(data.clone()).context(Data { data })
+    }
+    /// Send a command and return the text message of the response
+    fn text(&self, cmd: &str) -> Result<String> {
+        let data = self.command(cmd)?;
+        Ok(data.as_str().map(|s| s.to_string()).unwrap_or_else(|| data.to_string()))
+    }
+    /// Current board status
+    pub fn status(&self) -> Result<VpiStatus> {
+        self.typed("status")
+    }
+    /// Driver statistics
+    pub fn stats(&self) -> Result<VpiStats> {
+        self.typed("stats")
+    }
+    /// 96-bit UUID of the board
+    pub fn uuid(&self) -> Result<String> {
+        self.text("uuid")
+    }
+    /// Set fan speed 0-255
+    pub fn fan(&self, speed: u8) -> Result<String> {
+        self.text(&format!("fan {}", speed))
+    }
+    /// Get a value of the key store
+    pub fn get_key(&self, key: &str) -> Result<String> {
+        self.text(&format!("getkey {}", key))
+    }
+    /// Set a value of the key store
+    pub fn set_key(&self, key: &str, value: &str) -> Result<()> {
+        self.command(&format!("setkey {} {}", key, value)).map(|_| ())
+    }
+    /// Set a value of the key store expiring after `ttl` seconds
+    pub fn set_key_ttl(&self, key: &str, value: &str, ttl: u32) -> Result<()> {
+        self.command(&format!("setkeyex {} {} {}", key, ttl, value))
+            .map(|_| ())
+    }
+    /// Delete a key of the key store
+    pub fn del_key(&self, key: &str) -> Result<()> {
+        self.command(&format!("delkey {}", key)).map(|_| ())
+    }
+    /// Keys of the key store starting with `prefix`
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.typed(&format!("keys {}", prefix))
+    }
+    /// Reload vpid configuration
+    pub fn reload(&self) -> Result<()> {
+        self.command("reload").map(|_| ())
+    }
+    /// Stop vpid. The board is powered off or, if `reboot`, returned to booting state.
+    pub fn exit(&self, reboot: bool) -> Result<()> {
+        self.command(if reboot { "exit reboot" } else { "exit" })
+            .map(|_| ())
+    }
+}
+
+/// Take the `data` of a response or `Error::Command` if `"result":false`
+fn response_data(cmd: &str, mut resp: Value) -> Result<Value> {
+    let data = resp["data"].take();
+    if resp["result"].as_bool().unwrap_or(false) {
+        Ok(data)
+    } else {
+        Err(Error::Command {
+            cmd: cmd.to_string(),
+            data,
+        })
+    }
+}
+
+/// Persistent connection to vpid. Requests are sent with an id checked in the response.
+/// The session ends when dropped or with `quit()`.
+#[derive(Debug)]
+pub struct VpidSession {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+impl VpidSession {
+    /// Send a raw command and return the JSON envelope without the id
+    pub fn request(&mut self, cmd: &str) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let req = json!({ "id": id, "cmd": cmd.trim() });
+        self.stream
+            .write_all(format!("{}\n", req).as_bytes())
+            .context(Io)?;
+        self.stream.flush().context(Io)?;
+        let mut resp = String::new();
+        self.reader.read_line(&mut resp).context(Io)?;
+        let mut resp: Value = serde_json::from_str(resp.trim()).context(Json { response: resp })?;
+        let got = resp
+            .as_object_mut()
+            .and_then(|o| o.remove("id"))
+            .unwrap_or(Value::Null);
+        if got != json!(id) {
+            return Err(Error::Id { expected: id, got });
+        }
+        Ok(resp)
+    }
+    /// Send a command and return the `data` of the response
+    pub fn command(&mut self, cmd: &str) -> Result<Value> {
+        let resp = self.request(cmd)?;
+        response_data(cmd, resp)
+    }
+    /// Current board status
+    pub fn status(&mut self) -> Result<VpiStatus> {
+        let data = self.command("status")?;
+        serde_json::from_value(data.clone()).context(Data { data })
+    }
+    /// Driver statistics
+    pub fn stats(&mut self) -> Result<VpiStats> {
+        let data = self.command("stats")?;
+        serde_json::from_value(data.clone()).context(Data { data })
+    }
+    /// End the session
+    pub fn quit(mut self) -> Result<()> {
+        self.request("quit").map(|_| ())
+    }
+}
+
+/// Stream of events, each one a JSON object with the name in `"event"`.
+/// Ends when vpid closes the connection.
+#[derive(Debug)]
+pub struct Subscription {
+    session: VpidSession,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Value>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.session.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(serde_json::from_str(line.trim()).context(Json { response: line })),
+            Err(e) => Some(Err(Error::Io { source: e })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    /// Fake vpid on a socket of the temp dir answering `conns` connections with `handler`
+    fn fake_vpid<F>(name: &str, conns: usize, handler: F) -> (VpidClient, PathBuf)
+    where
+        F: Fn(BufReader<UnixStream>, UnixStream) + Send + 'static,
+    {
+        let sock =
+            std::env::temp_dir().join(format!("vpid-client-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&sock);
+        let listener = UnixListener::bind(&sock).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(conns) {
+                let stream = stream.unwrap();
+                handler(BufReader::new(stream.try_clone().unwrap()), stream);
+            }
+        });
+        (VpidClient::new(&sock), sock)
+    }
+
+    fn reply(mut stream: &UnixStream, resp: Value) {
+        stream.write_all(format!("{}\n", resp).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn typed_responses() {
+        let (client, sock) = fake_vpid("typed", 2, |mut reader, stream| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let data = match line.trim() {
+                "status" => serde_json::to_value(VpiStatus {
+                    rpm: 1200,
+                    is_running: true,
+                    ..Default::default()
+                })
+                .unwrap(),
+                _ => {
+                    json!({"retries": 1, "recovers": 0, "i2c_errors": 2, "status_checks": 10, "crc_errors": 0})
+                }
+            };
+            reply(&stream, json!({"result": true, "data": data}));
+        });
+        let status = client.status().unwrap();
+        let stats = client.stats().unwrap();
+        std::fs::remove_file(&sock).unwrap();
+        assert_eq!(status.rpm, 1200);
+        assert!(status.is_running);
+        assert_eq!(
+            (stats.retries, stats.i2c_errors, stats.status_checks),
+            (1, 2, 10)
+        );
+    }
+
+    #[test]
+    fn failed_command() {
+        let (client, sock) = fake_vpid("failed", 1, |mut reader, stream| {
+            reader.read_line(&mut String::new()).unwrap();
+            reply(&stream, json!({"result": false, "data": "Invalid command"}));
+        });
+        let res = client.fan(100);
+        std::fs::remove_file(&sock).unwrap();
+        match res {
+            Err(Error::Command { cmd, data }) => {
+                assert_eq!(cmd, "fan 100");
+                assert_eq!(data, json!("Invalid command"));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn session_ids() {
+        let (client, sock) = fake_vpid("ids", 1, |mut reader, stream| {
+            for offset in 0..2 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let req: Value = serde_json::from_str(&line).unwrap();
+                let id = req["id"].as_u64().unwrap() + offset;
+                reply(&stream, json!({"id": id, "result": true, "data": "1234"}));
+            }
+        });
+        let mut session = client.session().unwrap();
+        let first = session.command("uuid");
+        let second = session.command("uuid");
+        std::fs::remove_file(&sock).unwrap();
+        assert_eq!(first.unwrap(), json!("1234"));
+        match second {
+            Err(Error::Id { expected, got }) => assert_eq!((expected, got), (2, json!(3))),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (client, sock) = fake_vpid("timeout", 1, |_reader, _stream| {
+            thread::sleep(Duration::from_millis(500));
+        });
+        let res = client.timeout(Duration::from_millis(100)).status();
+        std::fs::remove_file(&sock).unwrap();
+        assert!(matches!(res, Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn subscription_ends_on_eof() {
+        let (client, sock) = fake_vpid("subscribe", 1, |mut reader, stream| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let req: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(req["cmd"], json!("subscribe button"));
+            reply(
+                &stream,
+                json!({"id": req["id"], "result": true, "data": "Subscribed"}),
+            );
+            reply(&stream, json!({"event": "button", "button": "pwr"}));
+            reply(&stream, json!({"event": "button", "button": "aux"}));
+        });
+        let events: Vec<Value> = client
+            .subscribe(&["button"])
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        std::fs::remove_file(&sock).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["button"], json!("aux"));
+    }
+}
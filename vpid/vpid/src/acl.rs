@@ -0,0 +1,198 @@
+//! Access control
+//! Socket file owner, group & mode and per command authorization of the clients.
+//! The daemon checks every command of the socket, HTTP & MQTT in one place, before it
+//! runs. Rules are checked in order, the first rule listing the command (or `*`) decides.
+//! Principals of `allow`: `all`, a user name, `@group` or a numeric uid matched with the
+//! peer credentials (SO_PEERCRED) of socket clients, `http` for HTTP clients and `mqtt`
+//! for MQTT commands. root and the daemon itself (rules, Lua, signals) are always
+//! allowed. Without rules only read only queries (status, stats, getkey...) are allowed,
+//! to everyone: the socket file is created with mode 0666 unless `mode` is set.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use std::ffi::CString;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use crate::cmd::{VpiOrigin,QUERY_VERBS};
+use nix::sys::socket::{getsockopt,sockopt::PeerCredentials};
+use nix::unistd::{Uid,Gid,User,Group,chown,getgrouplist};
+
+/// Mode of the socket file without `mode`, queries are open to everyone
+const DEFAULT_SOCKET_MODE: &str = "0666";
+
+/// Authorization rule of socket commands
+#[derive(DeserializePiecewiseDefault,JsonSchema,Default,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiAclRule {
+    /// Commands (first word) covered by the rule, `*` for all
+    pub commands: Vec<String>,
+    /// Principals allowed: `all`, user name, `@group` or uid
+    pub allow: Vec<String>,
+}
+
+/// Socket configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Default,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiSocketConfig {
+    /// Owner user of the socket file
+    pub owner: Option<String>,
+    /// Group of the socket file
+    pub group: Option<String>,
+    /// Permissions of the socket file in octal, e.g. "0660", 0666 if not set
+    pub mode: Option<String>,
+    /// Authorization rules
+    pub acl: Vec<VpiAclRule>,
+}
+
+impl VpiSocketConfig {
+    /// Apply owner, group & mode to the socket file
+    pub fn apply(&self, sock: &PathBuf) -> Result<(),String> {
+        let uid = match self.owner {
+            Some(ref o) => Some(User::from_name(o).ok().flatten().ok_or(format!("Unknown socket owner '{}'",o))?.uid),
+            None => None,
+        };
+        let gid = match self.group {
+            Some(ref g) => Some(Group::from_name(g).ok().flatten().ok_or(format!("Unknown socket group '{}'",g))?.gid),
+            None => None,
+        };
+        if uid.is_some() || gid.is_some() {
+            chown(sock, uid, gid).map_err(|e| format!("Socket chown failed: {}",e))?;
+        }
+        let m = self.mode.as_deref().unwrap_or(DEFAULT_SOCKET_MODE);
+        let mode = u32::from_str_radix(m.trim_start_matches("0o"),8).map_err(|_| format!("Invalid socket mode '{}'",m))?;
+        std::fs::set_permissions(sock, std::fs::Permissions::from_mode(mode)).map_err(|e| format!("Socket chmod failed: {}",e))?;
+        Ok(())
+    }
+    /// Check if a rule allows `principal` (http, mqtt) any command besides the queries
+    pub fn grants_commands(&self, principal: &str) -> bool {
+        self.acl.iter().any(|r| r.allow.iter().any(|p| p == "all" || p == "*" || p == principal)
+            && r.commands.iter().any(|c| !QUERY_VERBS.contains(&c.to_lowercase().as_str())))
+    }
+    /// Check if the origin can run the command, `query` if it only reads state
+    pub fn allowed(&self, origin: &VpiOrigin, cmd: &str, query: bool) -> bool {
+        let is = |principal: &str| match origin {
+            VpiOrigin::Socket { peer, .. } => peer.is(principal),
+            VpiOrigin::Http(_) => principal == "all" || principal == "*" || principal == "http",
+            VpiOrigin::Mqtt(_) => principal == "all" || principal == "*" || principal == "mqtt",
+            _ => true,
+        };
+        match origin {
+            VpiOrigin::Socket { peer, .. } if peer.uid == 0 => return true,
+            VpiOrigin::Socket { .. } | VpiOrigin::Http(_) | VpiOrigin::Mqtt(_) => {},
+            _ => return true,
+        }
+        if self.acl.is_empty() {
+            return query;
+        }
+        let verb = cmd.split_whitespace().next().unwrap_or("").to_lowercase();
+        match self.acl.iter().find(|r| r.commands.iter().any(|c| c == "*" || c.to_lowercase() == verb)) {
+            Some(rule) => rule.allow.iter().any(|p| is(p)),
+            None => false,
+        }
+    }
+}
+
+/// Credentials of a socket client
+#[derive(Debug,Clone)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: i32,
+    pub user: Option<String>,
+    pub groups: Vec<String>,
+}
+
+impl Peer {
+    /// Credentials of the client connected to `socket`
+    pub fn from_socket(socket: &UnixStream) -> Result<Peer,String> {
+        let cred = getsockopt(socket.as_raw_fd(), PeerCredentials).map_err(|e| format!("SO_PEERCRED failed: {}",e))?;
+        let user = User::from_uid(Uid::from_raw(cred.uid())).ok().flatten();
+        let mut gids = vec!(Gid::from_raw(cred.gid()));
+        if let Some(ref u) = user { // Supplementary groups of the user
+            if let Ok(name) = CString::new(u.name.as_str()) {
+                gids.extend(getgrouplist(&name, u.gid).unwrap_or_default());
+            }
+        }
+        let groups = gids.iter().filter_map(|g| Group::from_gid(*g).ok().flatten()).map(|g| g.name).collect();
+        Ok(Peer { uid: cred.uid(), pid: cred.pid(), user: user.map(|u| u.name), groups })
+    }
+    /// Check if the peer is the principal
+    fn is(&self, principal: &str) -> bool {
+        if principal == "all" || principal == "*" {
+            true
+        } else if let Some(g) = principal.strip_prefix('@') {
+            self.groups.iter().any(|pg| pg == g)
+        } else if let Ok(uid) = principal.parse::<u32>() {
+            self.uid == uid
+        } else {
+            self.user.as_deref() == Some(principal)
+        }
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,"uid:{}({}) pid:{}",self.uid,self.user.as_deref().unwrap_or("?"),self.pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(uid: u32, user: &str, groups: &[&str]) -> VpiOrigin {
+        let peer = Peer { uid, pid: 1, user: Some(user.to_string()), groups: groups.iter().map(|g| g.to_string()).collect() };
+        VpiOrigin::Socket { peer, exe: "test".to_string() }
+    }
+
+    fn rule(commands: &[&str], allow: &[&str]) -> VpiAclRule {
+        VpiAclRule { commands: commands.iter().map(|c| c.to_string()).collect(), allow: allow.iter().map(|a| a.to_string()).collect() }
+    }
+
+    #[test]
+    fn empty_acl_read_only() {
+        let cfg = VpiSocketConfig::default();
+        let pi = socket(1000,"pi",&["pi"]);
+        assert!(cfg.allowed(&pi,"status",true));
+        assert!(!cfg.allowed(&pi,"fan 100",false));
+        assert!(!cfg.allowed(&VpiOrigin::Http("127.0.0.1:5000".to_string()),"fan 100",false));
+        assert!(!cfg.allowed(&VpiOrigin::Mqtt("localhost:1883".to_string()),"setkey a b",false));
+        assert!(cfg.allowed(&VpiOrigin::Mqtt("localhost:1883".to_string()),"status",true));
+        assert!(cfg.allowed(&socket(0,"root",&[]),"exit",false));
+        assert!(cfg.allowed(&VpiOrigin::Rule("r".to_string()),"fan 100",false));
+        assert!(cfg.allowed(&VpiOrigin::Lua("l".to_string()),"setkey a b",false));
+    }
+
+    #[test]
+    fn first_rule_decides() {
+        let cfg = VpiSocketConfig { acl: vec!(rule(&["status","subscribe"],&["all"]), rule(&["fan","LED"],&["@vpi","mqtt","1001"]), rule(&["*"],&["admin"])), ..Default::default() };
+        let pi = socket(1000,"pi",&["pi","vpi"]);
+        let other = socket(1001,"other",&[]);
+        let admin = socket(1002,"admin",&[]);
+        let nobody = socket(65534,"nobody",&["nogroup"]);
+        assert!(cfg.allowed(&nobody,"status",true));
+        assert!(cfg.allowed(&VpiOrigin::Http("h".to_string()),"subscribe",true));
+        assert!(cfg.allowed(&pi,"fan 100",false));
+        assert!(cfg.allowed(&pi,"led 1",false));
+        assert!(cfg.allowed(&other,"FAN 10",false));
+        assert!(!cfg.allowed(&nobody,"fan 100",false));
+        assert!(!cfg.allowed(&admin,"fan 100",false)); // Decided by the fan rule
+        assert!(cfg.allowed(&admin,"exit",false));
+        assert!(!cfg.allowed(&pi,"exit",false));
+        assert!(cfg.allowed(&VpiOrigin::Mqtt("m".to_string()),"fan 100",false));
+        assert!(!cfg.allowed(&VpiOrigin::Http("h".to_string()),"fan 100",false));
+        assert!(!cfg.allowed(&VpiOrigin::Mqtt("m".to_string()),"getkey a",true)); // Only admin
+    }
+
+    #[test]
+    fn granted_commands() {
+        assert!(!VpiSocketConfig::default().grants_commands("http"));
+        let cfg = VpiSocketConfig { acl: vec!(rule(&["status","keys"],&["all"]), rule(&["fan"],&["mqtt"])), ..Default::default() };
+        assert!(!cfg.grants_commands("http"));
+        assert!(cfg.grants_commands("mqtt"));
+        let cfg = VpiSocketConfig { acl: vec!(rule(&["*"],&["all"])), ..Default::default() };
+        assert!(cfg.grants_commands("http"));
+    }
+}
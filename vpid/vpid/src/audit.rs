@@ -0,0 +1,157 @@
+//! Audit log of commands
+//! Every command handled by the daemon is recorded with its time, origin (socket peer
+//! pid/uid/exe, HTTP client, MQTT broker, Lua script, rule, signal or daemon), text, result
+//! and latency, as are the commands denied by the acl (result `denied`) and the
+//! shutdown, reboot & shell commands run by the rules. Records go to `file`, rotated at
+//! `max_size` keeping `keep` old files (`<file>.1` newest), or without a file to the log
+//! target `vpid::audit` (journal). Read only queries (status, stats, getkey...) are
+//! skipped unless `queries` is set.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use std::fs::{self,File,OpenOptions};
+use std::io::Write;
+use chrono::Local;
+use std::time::Duration;
+use crate::cmd::{VpiCommand,VpiOrigin};
+
+/// Audit configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiAuditConfig {
+    /// Audit file, the log is used if not set
+    pub file: Option<String>,
+    /// Size in bytes that triggers the rotation
+    pub max_size: u64,
+    /// Rotated files kept
+    pub keep: u32,
+    /// Record read only queries too
+    pub queries: bool,
+}
+
+impl Default for VpiAuditConfig {
+    fn default() -> Self {
+        VpiAuditConfig {
+            file: None,
+            max_size: 1024*1024,
+            keep: 5,
+            queries: false,
+        }
+    }
+}
+
+/// Action run by the daemon itself, e.g. the shell command of a rule
+#[derive(Debug,Clone)]
+pub struct AuditRecord {
+    pub origin: VpiOrigin,
+    pub text: String,
+    pub result: String,
+    pub latency: Duration,
+}
+
+/// Audit writer
+pub struct Audit {
+    cfg: VpiAuditConfig,
+    out: Option<File>,
+}
+
+impl Audit {
+    /// Create the audit, the file is opened on the first record
+    pub fn new(cfg: &VpiAuditConfig) -> Self {
+        Audit { cfg: cfg.clone(), out: None }
+    }
+    /// Change the configuration (reload)
+    pub fn configure(&mut self, cfg: &VpiAuditConfig) {
+        if *cfg != self.cfg {
+            self.cfg = cfg.clone();
+            self.out = None;
+        }
+    }
+    /// Record a handled command
+    pub fn record(&mut self, cmd: &VpiCommand) {
+        if cmd.body.is_query() && !self.cfg.queries {
+            return;
+        }
+        let result = match cmd.response() {
+            Some(r) => match serde_json::from_str::<serde_json::Value>(&r) {
+                Ok(v) if v["result"].as_bool() == Some(true) => "ok".to_string(),
+                Ok(v) => format!("error {}",v["data"]),
+                Err(_) => r,
+            },
+            None => "no response".to_string(),
+        };
+        self.write_record(&cmd.origin, &cmd.text, &result, cmd.created.elapsed());
+    }
+    /// Record a command denied by the acl, queries included
+    pub fn denied(&mut self, cmd: &VpiCommand) {
+        self.write_record(&cmd.origin, &cmd.text, "denied", cmd.created.elapsed());
+    }
+    /// Record an action of the daemon
+    pub fn record_action(&mut self, rec: &AuditRecord) {
+        self.write_record(&rec.origin, &rec.text, &rec.result, rec.latency);
+    }
+    /// Write a record to the file or the log
+    fn write_record(&mut self, origin: &VpiOrigin, text: &str, result: &str, latency: Duration) {
+        let latency = latency.as_secs_f64()*1000.0;
+        let line = format!("origin={} cmd={:?} result={:?} latency={:.1}ms",origin,text,result,latency);
+        let path = match self.cfg.file {
+            Some(ref f) => f.clone(),
+            None => { info!(target: "vpid::audit","{}",line); return; },
+        };
+        if let Err(e) = self.write(&path, &line) {
+            warn!("Audit file {} write failed:{}, record:{}",path,e,line);
+            self.out = None;
+        }
+    }
+    /// Append a line to the audit file, rotating it when full
+    fn write(&mut self, path: &str, line: &str) -> std::io::Result<()> {
+        if fs::metadata(path).map(|m| m.len() >= self.cfg.max_size).unwrap_or(false) {
+            self.out = None;
+            self.rotate(path)?;
+        }
+        if self.out.is_none() {
+            self.out = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        let out = self.out.as_mut().unwrap();
+        writeln!(out,"{} {}",Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%z"),line)
+    }
+    /// Shift <file>.N to <file>.N+1, dropping the oldest
+    fn rotate(&self, path: &str) -> std::io::Result<()> {
+        if self.cfg.keep == 0 {
+            return fs::remove_file(path);
+        }
+        for n in (1..self.cfg.keep).rev() {
+            let from = format!("{}.{}",path,n);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}",path,n+1))?;
+            }
+        }
+        fs::rename(path, format!("{}.1",path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::VpiCommandBody;
+
+    #[test]
+    fn denied_and_actions() {
+        let path = std::env::temp_dir().join(format!("vpid-audit-{}.log",std::process::id()));
+        let file = path.display().to_string();
+        let mut audit = Audit::new(&VpiAuditConfig { file: Some(file.clone()), max_size: 100, keep: 1, ..Default::default() });
+        let origin = VpiOrigin::Http("127.0.0.1:4000".to_string());
+        audit.record(&VpiCommand::new_nbc(VpiCommandBody::Metrics).with_origin(origin.clone(),"metrics")); // Query skipped
+        audit.denied(&VpiCommand::new_nbc(VpiCommandBody::Metrics).with_origin(origin,"metrics"));
+        let log = fs::read_to_string(&path).unwrap();
+        assert!(log.contains(r#"origin=http(127.0.0.1:4000) cmd="metrics" result="denied""#));
+        assert_eq!(log.lines().count(), 1);
+        audit.record_action(&AuditRecord { origin: VpiOrigin::Rule("off".to_string()), text: "/sbin/poweroff".to_string(), result: "exit code 0".to_string(), latency: Duration::from_millis(3) });
+        let log = fs::read_to_string(&path).unwrap(); // Rotated
+        assert!(log.contains(r#"origin=rule(off) cmd="/sbin/poweroff" result="exit code 0" latency=3.0ms"#));
+        assert!(fs::read_to_string(format!("{}.1",file)).unwrap().contains("denied"));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.1",file));
+    }
+}
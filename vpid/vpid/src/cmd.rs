@@ -1,9 +1,12 @@
 use vpi::cmd::{VpiCmd,VpiCmdOutput};
-use crossbeam_channel::{Sender,Receiver};
+use crossbeam_channel::{Sender,bounded};
 use std::os::raw::c_int;
-use std::time::Duration;
+use std::time::{Duration,Instant};
+use std::cell::RefCell;
+use std::fmt;
 use crate::error::{Result,ResultExt,OptionExt,CommandParse,CommandSend,CommandRecv,JsonError};
 use serde_json::Value;
+use crate::acl::Peer;
 
 /// Type to define possible commands managed by Vpi
 #[derive(Debug)]
@@ -14,12 +17,73 @@ pub enum VpiCommandBody {
     Signal(c_int),
     /// Get Key-value storage
     GetKey(String),
-    /// Set Key-value storage
-    SetKey(String,String),
+    /// Set Key-value storage, with TTL in seconds
+    SetKey(String,String,Option<u32>),
+    /// Delete a key
+    DelKey(String),
+    /// List keys by prefix
+    Keys(String),
     /// Exit
     Exit(bool),
     /// Reload config
     ReloadConfig,
+    /// Temperature & fan regulation state
+    Thermal,
+    /// Metrics of board, fan & engine
+    Metrics,
+    /// Event stream of a socket session, authorized by the daemon
+    Subscribe,
+}
+
+impl VpiCommandBody {
+    /// Commands that only read state
+    pub fn is_query(&self) -> bool {
+        matches!(self, VpiCommandBody::Basic(VpiCmd::Nop) | VpiCommandBody::Basic(VpiCmd::Status) |
+            VpiCommandBody::Basic(VpiCmd::Stats) | VpiCommandBody::Basic(VpiCmd::Uuid) |
+            VpiCommandBody::GetKey(_) | VpiCommandBody::Keys(_) | VpiCommandBody::Thermal | VpiCommandBody::Metrics | VpiCommandBody::Subscribe)
+    }
+}
+
+/// First words of the read only queries (`is_query`)
+pub const QUERY_VERBS: [&str; 9] = ["nop","status","stats","uuid","getkey","keys","thermal","metrics","subscribe"];
+
+/// Who sent a command
+#[derive(Debug,Clone)]
+pub enum VpiOrigin {
+    /// vpid itself (boot sequence)
+    Daemon,
+    /// OS signal
+    Signal,
+    /// Config file watcher
+    Watch,
+    /// Socket client
+    Socket { peer: Peer, exe: String },
+    /// HTTP client address
+    Http(String),
+    /// MQTT broker
+    Mqtt(String),
+    /// Lua script of a rule
+    Lua(String),
+    /// Lua mini service
+    Service(String),
+    /// Command sequence of a rule
+    Rule(String),
+}
+
+impl fmt::Display for VpiOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VpiOrigin::Daemon => write!(f,"daemon"),
+            VpiOrigin::Signal => write!(f,"signal"),
+            VpiOrigin::Watch => write!(f,"watch"),
+            VpiOrigin::Socket { peer, exe } => write!(f,"socket(pid={},uid={},exe={})",peer.pid,peer.uid,exe),
+            VpiOrigin::Http(addr) => write!(f,"http({})",addr),
+            VpiOrigin::Mqtt(broker) => write!(f,"mqtt({})",broker),
+            VpiOrigin::Lua(name) => write!(f,"lua({})",name),
+            VpiOrigin::Service(name) => write!(f,"service({})",name),
+            VpiOrigin::Rule(name) => write!(f,"rule({})",name),
+        }
+    }
 }
 
 const VPI_COMMAND_TIMEOUT : Duration = Duration::from_secs(2);
@@ -28,16 +92,24 @@ const VPI_COMMAND_TIMEOUT : Duration = Duration::from_secs(2);
 #[derive(Debug)]
 pub struct VpiCommand {
     pub body: VpiCommandBody,
-    back_channel: Option<Sender<String>>
+    back_channel: Option<Sender<String>>,
+    /// Sender of the command
+    pub origin: VpiOrigin,
+    /// Time the command was created
+    pub created: Instant,
+    /// Command as received
+    pub text: String,
+    /// Response sent, for the audit
+    response: RefCell<Option<String>>,
 }
 
 /// Parse a command from string
-pub fn parse_command(s:&String,bc_sender:&Sender<String>) -> Result<VpiCommand> {
+pub fn parse_command(s:&String,bc_sender:&Sender<String>,origin:&VpiOrigin) -> Result<VpiCommand> {
      
     // Rusty version
     VpiCommand::from_string(s, Some(bc_sender.clone())).or_else(|| {
             VpiCmd::from_string(s).and_then(|cmd| Some( VpiCommand::new(VpiCommandBody::Basic(cmd),Some(bc_sender.clone())) ) )
-    }).context(CommandParse { cmd: s})
+    }).map(|cmd| cmd.with_origin(origin.clone(),s)).context(CommandParse { cmd: s})
 
     // 1st try internal server commands
     // let mut vpicommand_opt=VpiCommand::from_string(&s,Some(bc_sender.clone()));
@@ -50,14 +122,18 @@ pub fn parse_command(s:&String,bc_sender:&Sender<String>) -> Result<VpiCommand>
     // vpicommand_opt
 }
 
-pub fn exec_command(s:&String,cmd_sender:&Sender<VpiCommand>,bc_sender:&Sender<String>,bc_recv: &Receiver<String>) -> Result<String> {
-    parse_command(s, bc_sender).and_then( |cmd| {
+/// Send a command and wait for the response.
+/// Each request has its own back channel, a late response after the timeout is dropped
+/// with the channel and never read by another request.
+pub fn exec_command(s:&String,cmd_sender:&Sender<VpiCommand>,origin:&VpiOrigin) -> Result<String> {
+    let (bc_sender,bc_recv) = bounded::<String>(1); // Back channel for the response
+    parse_command(s, &bc_sender, origin).and_then( |cmd| {
         cmd_sender.send_timeout(cmd, VPI_COMMAND_TIMEOUT).context(CommandSend)?;
         bc_recv.recv_timeout(VPI_COMMAND_TIMEOUT).context(CommandRecv)
     })
 }
-pub fn exec_command_json(s:&String,cmd_sender:&Sender<VpiCommand>,bc_sender:&Sender<String>,bc_recv: &Receiver<String>) -> Result<serde_json::Value> {
-    let val=exec_command(s, cmd_sender, bc_sender, bc_recv)?;
+pub fn exec_command_json(s:&String,cmd_sender:&Sender<VpiCommand>,origin:&VpiOrigin) -> Result<serde_json::Value> {
+    let val=exec_command(s, cmd_sender, origin)?;
     let jval : Value = serde_json::from_str(val.as_str()).context(JsonError)?;
     Ok(jval)
 }
@@ -66,11 +142,26 @@ pub fn exec_command_json(s:&String,cmd_sender:&Sender<VpiCommand>,bc_sender:&Sen
 impl VpiCommand {
 
     pub fn new(b: VpiCommandBody,bc: Option<Sender<String>> ) -> Self {
+        let text=format!("{:?}",b);
         Self {
             body: b,
             back_channel: bc,
+            origin: VpiOrigin::Daemon,
+            created: Instant::now(),
+            text,
+            response: RefCell::new(None),
         }
     }
+    /// Set the origin and the text of the command
+    pub fn with_origin(mut self, origin: VpiOrigin, text: &str) -> Self {
+        self.origin=origin;
+        self.text=text.to_string();
+        self
+    }
+    /// Response sent if any
+    pub fn response(&self) -> Option<String> {
+        self.response.borrow().clone()
+    }
     #[inline]
     pub fn new_nbc(b: VpiCommandBody) -> Self {
         Self::new(b,None)
@@ -96,6 +187,15 @@ impl VpiCommand {
             "reload" => {
                 Some(Self::new(VpiCommandBody::ReloadConfig,bc))
             },
+            "thermal" => {
+                Some(Self::new(VpiCommandBody::Thermal,bc))
+            },
+            "metrics" => {
+                Some(Self::new(VpiCommandBody::Metrics,bc))
+            },
+            "subscribe" => {
+                Some(Self::new(VpiCommandBody::Subscribe,bc))
+            },
             "getkey" => {
                 if v.len() >= 2 {
                     Some(Self::new(VpiCommandBody::GetKey(v[1].to_string()),bc))
@@ -107,11 +207,27 @@ impl VpiCommand {
             "setkey" => {
                 if v.len() >=3 {
                     let value=v[2..].join(" "); //to_string();
-                    Some(Self::new(VpiCommandBody::SetKey(v[1].to_string(),value),bc))
+                    Some(Self::new(VpiCommandBody::SetKey(v[1].to_string(),value,None),bc))
+                } else {
+                    None
+                }
+            },
+            "setkeyex" => {
+                match (v.len(), v.get(2).and_then(|t| t.parse::<u32>().ok())) {
+                    (l,Some(ttl)) if l >= 4 => Some(Self::new(VpiCommandBody::SetKey(v[1].to_string(),v[3..].join(" "),Some(ttl)),bc)),
+                    _ => None
+                }
+            },
+            "delkey" => {
+                if v.len() >= 2 {
+                    Some(Self::new(VpiCommandBody::DelKey(v[1].to_string()),bc))
                 } else {
                     None
                 }
             },
+            "keys" => {
+                Some(Self::new(VpiCommandBody::Keys(v.get(1).unwrap_or(&"").to_string()),bc))
+            },
             "exit" => {
                 let reboot:bool= v.len() >= 2 && v[1]=="reboot";
                 Some(Self::new(VpiCommandBody::Exit(reboot),bc))
@@ -122,8 +238,9 @@ impl VpiCommand {
 
 
     pub fn send_response(&self,response:String) {
+        *self.response.borrow_mut()=Some(response.clone());
         if let Some(bc) = &self.back_channel {
-            let _=bc.send(response);
+            let _=bc.try_send(response); // Never block if the requester is gone
         }
     }
     pub fn send_output(&self,out:&VpiCmdOutput) {
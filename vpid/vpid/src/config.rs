@@ -1,17 +1,42 @@
 /// Configuration module for vpid
 /// Abstract & parse configuration options
-use serde::Deserialize;
+use serde::{Deserialize,Serialize};
 use serde_piecewise_default::DeserializePiecewiseDefault;
-use std::path::{PathBuf};
+use schemars::JsonSchema;
+use std::path::{Path,PathBuf};
+use std::os::unix::fs::PermissionsExt;
 use std::fs;
+use std::collections::BTreeMap;
 use vpi::VpiTimes;
 
 // Crate used
 use crate::fan::VpiFanConfig;
-use crate::error::{Result,ResultExt,ReadConfig,ParseConfig};
+use crate::http::VpiHttpConfig;
+use crate::mqtt::VpiMqttConfig;
+use crate::acl::VpiSocketConfig;
+use crate::audit::VpiAuditConfig;
+use crate::keys::VpiKeysConfig;
+use crate::watch::VpiWatchConfig;
+use crate::notify::VpiWebhook;
+use crate::engine::check_lua;
+use crate::cmd::{VpiOrigin,parse_command};
+use crate::error::{Error,Result,ResultExt,ReadConfig,ParseConfig,ConfigInclude};
+use serde_yaml::Value;
+
+/// Check that the program of a command line exists and is executable
+fn check_command(cmd: &str) -> std::result::Result<(),String> {
+    let prog = cmd.split_whitespace().next().ok_or_else(|| "empty command".to_string())?;
+    let executable = |p: &Path| p.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false);
+    let found = if prog.contains('/') {
+        executable(Path::new(prog))
+    } else {
+        std::env::var_os("PATH").map(|paths| std::env::split_paths(&paths).any(|d| executable(&d.join(prog)))).unwrap_or(false)
+    };
+    if found { Ok(()) } else { Err(format!("program {} not found or not executable",prog)) }
+}
 
 /// Rule types
-#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, PartialEq, Eq, Debug)]
 pub enum VpiRuleType {
     /// Execute system shutdown (lauch command)
     Shutdown,
@@ -21,17 +46,47 @@ pub enum VpiRuleType {
     Shell,
     /// Run Lua script
     Lua,
+    /// Send a sequence of vpid commands
+    Commands,
+    /// HTTP POST to `webhook`
+    Webhook,
     // No operation rule
     Nop
 }
 
+/// When a rule fires while its condition is true
+#[derive(Deserialize, Serialize, JsonSchema, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum VpiTrigger {
+    /// Once when the condition becomes true
+    #[serde(rename = "edge")]
+    Edge,
+    /// On every check while the condition is true
+    #[serde(rename = "level")]
+    Level,
+}
+
+/// What a command sequence does when a step fails
+#[derive(Deserialize, Serialize, JsonSchema, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum VpiOnError {
+    /// Skip the remaining steps
+    #[serde(rename = "stop")]
+    Stop,
+    /// Run the remaining steps
+    #[serde(rename = "continue")]
+    Continue,
+}
+
 /// Definiton of a Vpid Rule
-#[derive(DeserializePiecewiseDefault, PartialEq, Eq, Debug)]
+#[derive(DeserializePiecewiseDefault, JsonSchema, PartialEq, Eq, Debug)]
+#[schemars(default,deny_unknown_fields)]
 pub struct VpiRule {
     /// Name of the rule for reference
     pub name:    String,
     /// Condition of the rule (expression)
     pub when:    String, 
+    /// Key watched, the rule is checked when the key is set or deleted instead of
+    /// on status changes. `prefix*` watches a namespace
+    pub on_key:  Option<String>,
     /// Kind of the rule see *VpiRuleType*
     pub kind:    VpiRuleType,
     /// Script to execute (Lua or shell)
@@ -40,6 +95,27 @@ pub struct VpiRule {
     pub asyncr:  bool,
     /// Script execution timeout
     pub timeout: u32,
+    /// Fire on the edge or at every poll (level) while the condition is true
+    pub trigger: VpiTrigger,
+    /// Rules with higher priority are checked first, same priority in file order
+    pub priority: i32,
+    /// Keep checking the next rules after this one fires
+    #[serde(rename = "continue")]
+    pub cont: bool,
+    /// Min time in seconds between fires
+    pub cooldown: u32,
+    /// Rule checked
+    pub enabled: bool,
+    /// Time in ms the condition must stay true before firing
+    pub debounce: u32,
+    /// Commands rules: vpid commands sent in order, `wait <ms>` pauses
+    pub commands: Vec<String>,
+    /// Commands rules: pause in ms between the steps
+    pub delay: u32,
+    /// Commands rules: stop or continue when a step fails
+    pub on_error: VpiOnError,
+    /// Webhook rules: target of the POST
+    pub webhook: Option<VpiWebhook>,
 }
 
 /// Default trait implementation for VpiRule
@@ -48,18 +124,32 @@ impl Default for VpiRule {
         VpiRule {
             name: "no name".to_string(),
             when: "".to_string(),
+            on_key: None,
             kind: VpiRuleType::Nop,
             script: None,
             asyncr: false,
             timeout: 0,
+            trigger: VpiTrigger::Edge,
+            priority: 0,
+            cont: false,
+            cooldown: 0,
+            enabled: true,
+            debounce: 0,
+            commands: vec!(),
+            delay: 0,
+            on_error: VpiOnError::Stop,
+            webhook: None,
         }
     }
 }
 
 /// Minservice definition in lua
-#[derive(DeserializePiecewiseDefault, PartialEq, Eq, Debug)]
+#[derive(DeserializePiecewiseDefault, JsonSchema, PartialEq, Eq, Debug, Clone)]
+#[schemars(default,deny_unknown_fields)]
 pub struct VpiMiniService {
+    /// Name of the service for reference
     pub name: String,
+    /// Lua script, runs until it returns or `vpi_test_cancel()` is true
     pub script: String,
 }
 ///Default implementa for VpiMiniService
@@ -72,23 +162,55 @@ impl Default for VpiMiniService {
     }
 }
 /// Configurarion file structure 
-#[derive(DeserializePiecewiseDefault, Debug)]
+#[derive(DeserializePiecewiseDefault, JsonSchema, Debug)]
+#[schemars(default,deny_unknown_fields)]
 pub struct VpiConfig {
+    /// Shutdown command of the Shutdown rules
     pub shutdown_command:   String,
+    /// Reboot command of the Reboot rules
     pub reboot_command:     String,
+    /// Shell to run commands & shell scripts
     pub shell:              String,
+    /// Max time for a short click in ms
     pub short_time:         u16,
+    /// Min time between consecutive clicks in ms
     pub space_time:         u16,
+    /// Grace time for shutdown/halt in seconds
     pub grace_time:         u8,
+    /// Continuous push for hard shutdown in seconds
     pub hold_time:          u8,
+    /// Polling time in ms, shorter than space_time
     pub poll_time:          Option<u32>,
+    /// Wake after power off in minutes, 0 disabled
     pub wake:               u16,
+    /// Wake by IRQ
     pub wake_irq:           bool,
+    /// Watchdog time in seconds, 0 disabled
     pub watchdog:           u8,
+    /// Feed the watchdog automatically
     pub watchdog_autofeed:  bool,
+    /// On a signal exit power off the board after grace_time, else return it to booting state
+    pub poweroff_onexit:    bool,
+    /// Rules checked on status & key changes
     pub rules:              Vec<VpiRule>,
+    /// Fan control
     pub fan:                Option<VpiFanConfig>,
+    /// Lua mini services
     pub services:           Vec<VpiMiniService>,
+    /// HTTP REST API
+    pub http:               Option<VpiHttpConfig>,
+    /// MQTT client
+    pub mqtt:               Option<VpiMqttConfig>,
+    /// Socket access control
+    pub socket:             VpiSocketConfig,
+    /// Audit log of commands
+    pub audit:              VpiAuditConfig,
+    /// Key-value store
+    pub keys:               VpiKeysConfig,
+    /// Config file watcher
+    pub watch:              Option<VpiWatchConfig>,
+    /// Webhooks posting the daemon events
+    pub notify:             Vec<VpiWebhook>,
 }
 
 // Just retrun default values
@@ -105,23 +227,289 @@ impl Default for VpiConfig {
             poll_time:  None,
             watchdog:   0,
             watchdog_autofeed: true,
+            poweroff_onexit: false,
             rules:      vec!(),
             fan:        None,
             wake:       0u16,
             wake_irq:   false,
             services:   vec!(),
+            http:       None,
+            mqtt:       None,
+            socket:     VpiSocketConfig::default(),
+            audit:      VpiAuditConfig::default(),
+            keys:       VpiKeysConfig::default(),
+            watch:      None,
+            notify:     vec!(),
+
+        }
+    }
+}
+
+/// Ranges of the schema values checked by `validate`: pointer, minimum & maximum
+const SCHEMA_RANGES: &[(&str,i64,Option<i64>)] = &[
+    ("/properties/short_time",21,None),
+    ("/properties/space_time",101,None),
+    ("/properties/poll_time",1,None),
+    ("/definitions/VpiFanConfig/properties/pins",2,Some(4)),
+    ("/definitions/VpiFanConfig/properties/divisor",1,None),
+    ("/definitions/VpiFanConfig/properties/sample",1,None),
+    ("/definitions/VpiFanConfig/properties/pwm_freq",2,Some(62500)),
+];
+
+/// Prefix of the environment overrides, `VPID_CFG_FAN__MODE=Pi` sets `fan.mode`. Not
+/// just `VPID_` as other variables (`VPID_VERSION` of buildall.sh) would become keys
+const ENV_PREFIX: &str = "VPID_CFG_";
+
+/// Top level lists appended by the layers, other lists are replaced
+const APPENDED_LISTS: &[&str] = &["rules","services"];
+
+/// Config file and its layers. Files matching the `include` patterns (relative to the
+/// config file directory) are merged in name order: mappings are merged, `rules` and
+/// `services` appended, other lists and scalars overridden. Then `VPID_CFG_A__B`
+/// environment variables and `--set a.b=value` flags override single values.
+#[derive(Debug,Clone)]
+pub struct VpiConfigSource {
+    pub path: PathBuf,
+    /// Overrides `key.path=value` of the command line
+    pub sets: Vec<String>,
+}
+
+/// Merge a layer into the configuration, `top` for the root mapping
+fn merge(base: &mut Value, layer: Value, top: bool) {
+    match (base, layer) {
+        (Value::Mapping(b), Value::Mapping(l)) => {
+            for (k,v) in l {
+                let append = top && k.as_str().is_some_and(|k| APPENDED_LISTS.contains(&k));
+                match (b.get_mut(&k), v) {
+                    (Some(Value::Sequence(bs)), Value::Sequence(ls)) if append => bs.extend(ls),
+                    (Some(bv), v) => merge(bv, v, false),
+                    (None, v) => { b.insert(k, v); },
+                }
+            }
+        },
+        (b, l) => *b = l,
+    }
+}
 
+/// Set the value of a dotted path, list items by index (`rules.0.when`)
+fn set_path(node: &mut Value, path: &[&str], value: &str) {
+    let (key,rest) = match path.split_first() {
+        Some(p) => p,
+        None => { // Typed as YAML scalar: numbers & booleans, strings otherwise
+            *node = serde_yaml::from_str(value).unwrap_or_else(|_| Value::from(value));
+            return;
+        }
+    };
+    if let Value::Sequence(seq) = node {
+        if let Some(item) = key.parse::<usize>().ok().and_then(|i| seq.get_mut(i)) {
+            return set_path(item, rest, value);
+        }
+    }
+    if !node.is_mapping() {
+        *node = Value::Mapping(Default::default());
+    }
+    if let Value::Mapping(map) = node {
+        set_path(map.entry(Value::from(*key)).or_insert(Value::Null), rest, value);
+    }
+}
+
+impl VpiConfigSource {
+    /// Read & check a YAML file, type errors are reported with their position
+    fn read(file: &Path) -> Result<Value> {
+        let yml = fs::read_to_string(file).context(ReadConfig { filename: file })?;
+        serde_yaml::from_str::<VpiConfig>(&yml).context(ParseConfig { filename: file })?;
+        serde_yaml::from_str(&yml).context(ParseConfig { filename: file })
+    }
+    /// Include patterns of the config file, relative to its directory
+    fn includes(base: &Value, dir: &Path) -> Vec<String> {
+        let patterns = match base.get("include") {
+            Some(Value::String(p)) => vec!(p.clone()),
+            Some(Value::Sequence(s)) => s.iter().filter_map(|p| p.as_str().map(|p| p.to_string())).collect(),
+            _ => vec!(),
+        };
+        patterns.into_iter().map(|p| if p.starts_with('/') { p } else { dir.join(p).to_string_lossy().to_string() }).collect()
+    }
+    /// Directory of the config file
+    fn dir(&self) -> PathBuf {
+        match self.path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+    /// Directories of the include patterns
+    pub fn include_dirs(&self) -> Vec<PathBuf> {
+        let base = match Self::read(&self.path) {
+            Ok(b) => b,
+            Err(_) => return vec!(),
+        };
+        Self::includes(&base, &self.dir()).iter().filter_map(|p| Path::new(p).parent().map(|d| d.to_path_buf())).collect()
+    }
+    /// Load the config file with its includes & overrides, unknown keys are logged
+    pub fn load(&self) -> Result<VpiConfig> {
+        let (cfg,unknown) = self.load_checked()?;
+        for key in unknown {
+            warn!("Unknown config key {} ignored",key);
+        }
+        Ok(cfg)
+    }
+    /// Load the config file failing on the problems found by `validate`, `warnings` don't fail
+    pub fn load_valid(&self) -> Result<VpiConfig> {
+        let cfg = self.load()?;
+        let problems = cfg.validate();
+        if problems.is_empty() {
+            Ok(cfg)
+        } else {
+            Err(Error::InvalidConfig { filename: self.path.clone(), problems })
+        }
+    }
+    /// Load the config file, returning also the unknown keys (`fan.smaple`)
+    pub fn load_checked(&self) -> Result<(VpiConfig,Vec<String>)> {
+        self.load_env(&std::env::vars().collect())
+    }
+    /// `load_checked` with the environment variables `env`
+    fn load_env(&self, env: &BTreeMap<String,String>) -> Result<(VpiConfig,Vec<String>)> {
+        let mut cfg = Self::read(&self.path)?;
+        for pattern in Self::includes(&cfg, &self.dir()) {
+            let mut files: Vec<PathBuf> = glob::glob(&pattern).context(ConfigInclude { pattern: pattern.as_str() })?.filter_map(|f| f.ok()).collect();
+            files.sort();
+            for file in files {
+                debug!("Including config file {}",file.display());
+                merge(&mut cfg, Self::read(&file)?, true);
+            }
         }
+        if let Value::Mapping(ref mut map) = cfg {
+            map.remove(&Value::from("include"));
+        }
+        for (name,value) in env.iter() {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                let key = key.to_lowercase();
+                set_path(&mut cfg, &key.split("__").collect::<Vec<&str>>(), value);
+            }
+        }
+        for set in self.sets.iter() {
+            match set.split_once('=') {
+                Some((key,value)) => set_path(&mut cfg, &key.trim().split('.').collect::<Vec<&str>>(), value),
+                None => return Err(Error::ConfigOverride { set: set.clone() }),
+            }
+        }
+        let mut unknown = vec!();
+        let cfg = serde_ignored::deserialize(cfg, |path| { // Option levels are shown as '?'
+            unknown.push(path.to_string().split('.').filter(|p| *p != "?").collect::<Vec<&str>>().join("."));
+        }).context(ParseConfig { filename: &self.path })?;
+        Ok((cfg,unknown))
     }
 }
 
 impl VpiConfig {
 
-    pub fn load(cfile : &PathBuf) -> Result<VpiConfig> {
-        //let yml = fs::read_to_string(cfile).and_then(|yml| serde_yaml::from_str );
-        let yml = fs::read_to_string(cfile).context(ReadConfig { filename: cfile })?;
-        let vpi = serde_yaml::from_str(yml.as_str()).context(ParseConfig { filename:cfile})?;
-        Ok(vpi)
+    /// JSON Schema of the config file (yaml-language-server, CI lint)
+    pub fn schema() -> serde_json::Value {
+        let mut schema = serde_json::to_value(schemars::schema_for!(VpiConfig)).unwrap_or_default();
+        for (pointer,min,max) in SCHEMA_RANGES {
+            if let Some(serde_json::Value::Object(prop)) = schema.pointer_mut(pointer) {
+                prop.insert("minimum".to_string(), (*min).into());
+                if let Some(max) = max {
+                    prop.insert("maximum".to_string(), (*max).into());
+                }
+            }
+        }
+        schema["properties"]["include"] = serde_json::json!({
+            "description": "Glob pattern(s) of config fragments, relative to the config file directory",
+            "anyOf": [ { "type": "string" }, { "type": "array", "items": { "type": "string" } } ]
+        });
+        schema
+    }
+
+    /// SoC temperature in millicelsius of the fan `thermal_path`, or the default one
+    pub fn get_temp(&self) -> Option<i32> {
+        match self.fan {
+            Some(ref fan) => fan.read_temp(),
+            None => VpiFanConfig::default().read_temp(),
+        }
+    }
+    /// Semantic problems of the configuration: ranges, scripts & commands
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors=vec!();
+        if self.short_time <= 20 {
+            errors.push(format!("short_time {} must be > 20 ms",self.short_time));
+        }
+        if self.space_time <= 100 {
+            errors.push(format!("space_time {} must be > 100 ms",self.space_time));
+        }
+        if let Some(p) = self.poll_time {
+            if p >= self.space_time as u32 {
+                errors.push(format!("poll_time {} must be shorter than space_time {}",p,self.space_time));
+            }
+        }
+        if let Some(ref fan) = self.fan {
+            errors.extend(fan.validate());
+        }
+        for (i,rule) in self.rules.iter().enumerate() {
+            if self.rules[..i].iter().any(|r| r.name == rule.name) {
+                errors.push(format!("rule [{}] name duplicated, the rules share their trigger state",rule.name));
+            }
+            if rule.on_key.is_some() && (rule.trigger == VpiTrigger::Level || rule.debounce > 0) {
+                errors.push(format!("rule [{}] trigger & debounce don't apply to on_key rules",rule.name));
+            }
+            let when_required = rule.on_key.is_none() || !rule.when.trim().is_empty();
+            if when_required {
+                if let Err(e) = check_lua(&rule.when, true) {
+                    errors.push(format!("rule [{}] when: {}",rule.name,e));
+                }
+            }
+            match (&rule.kind, &rule.script) {
+                (VpiRuleType::Lua, Some(script)) => if let Err(e) = check_lua(script, false) {
+                    errors.push(format!("rule [{}] script: {}",rule.name,e));
+                },
+                (VpiRuleType::Lua, None) | (VpiRuleType::Shell, None) => errors.push(format!("rule [{}] has no script",rule.name)),
+                _ => {},
+            }
+            match (&rule.kind, &rule.webhook) {
+                (VpiRuleType::Webhook, Some(hook)) => errors.extend(hook.validate(&format!("rule [{}] webhook",rule.name))),
+                (VpiRuleType::Webhook, None) => errors.push(format!("rule [{}] has no webhook",rule.name)),
+                _ => {},
+            }
+            if rule.kind == VpiRuleType::Commands {
+                if rule.commands.is_empty() {
+                    errors.push(format!("rule [{}] has no commands",rule.name));
+                }
+                let (bc,_) = crossbeam_channel::bounded::<String>(1);
+                for step in rule.commands.iter() {
+                    let valid = match step.strip_prefix("wait ") {
+                        Some(ms) => ms.trim().parse::<u64>().is_ok(),
+                        None => parse_command(step, &bc, &VpiOrigin::Rule(rule.name.clone())).is_ok(),
+                    };
+                    if !valid {
+                        errors.push(format!("rule [{}] command '{}' not valid",rule.name,step));
+                    }
+                }
+            }
+        }
+        if let Some(ref http) = self.http {
+            errors.extend(http.validate());
+        }
+        for (i,hook) in self.notify.iter().enumerate() {
+            errors.extend(hook.validate(&format!("notify {}",i)));
+        }
+        for ser in self.services.iter() {
+            if let Err(e) = check_lua(&ser.script, false) {
+                errors.push(format!("service [{}] script: {}",ser.name,e));
+            }
+        }
+        errors
+    }
+    /// Problems of the system (missing programs & files), only warned as the daemon can run
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings=vec!();
+        for (name,cmd) in [("shell",&self.shell),("shutdown_command",&self.shutdown_command),("reboot_command",&self.reboot_command)].iter() {
+            if let Err(e) = check_command(cmd) {
+                warnings.push(format!("{}: {}",name,e));
+            }
+        }
+        if let Some(ref fan) = self.fan {
+            warnings.extend(fan.warnings());
+        }
+        warnings
     }
     pub fn times(&self) -> VpiTimes {
         VpiTimes::new(self.short_time,self.space_time,self.hold_time,self.grace_time)
@@ -135,3 +523,74 @@ impl VpiConfig {
     } 
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_layers() {
+        let mut base = yaml("short_time: 250\nfan: {mode: Pi, sample: 3}\nrules: [{name: a}]\nsocket: {acl: [{commands: ['*'], allow: [root]}]}\n");
+        merge(&mut base, yaml("short_time: 300\nfan: {sample: 5}\nrules: [{name: b}]\nservices: [{name: s}]\nsocket: {acl: [{commands: [status], allow: [all]}]}\n"), true);
+        assert_eq!(base, yaml("short_time: 300\nfan: {mode: Pi, sample: 5}\nrules: [{name: a}, {name: b}]\nservices: [{name: s}]\nsocket: {acl: [{commands: [status], allow: [all]}]}\n"));
+    }
+
+    #[test]
+    fn nested_lists_replaced() {
+        let mut base = yaml("rules: [{name: a, commands: [led on]}]\nnotify: [{url: 'http://a'}]\n");
+        merge(&mut base, yaml("notify: [{url: 'http://b'}]\n"), true);
+        assert_eq!(base, yaml("rules: [{name: a, commands: [led on]}]\nnotify: [{url: 'http://b'}]\n"));
+    }
+
+    #[test]
+    fn set_paths() {
+        let mut cfg = yaml("rules: [{name: a}]\n");
+        set_path(&mut cfg, &["fan","mode"], "Pi");
+        set_path(&mut cfg, &["short_time"], "300");
+        set_path(&mut cfg, &["rules","0","when"], "short == 2");
+        assert_eq!(cfg, yaml("rules: [{name: a, when: short == 2}]\nfan: {mode: Pi}\nshort_time: 300\n"));
+    }
+
+    #[test]
+    fn layers_and_overrides() {
+        let dir = std::env::temp_dir().join(format!("vpid-config-{}",std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("vpid.yml"), "include: conf.d/*.yml\nshort_time: 200\nrules:\n  - name: base\n").unwrap();
+        fs::write(dir.join("conf.d/10-rules.yml"), "rules:\n  - name: extra\nsocket:\n  acl:\n    - commands: ['*']\n      allow: [all]\n").unwrap();
+        fs::write(dir.join("conf.d/20-acl.yml"), "socket:\n  acl:\n    - commands: [status]\n      allow: [root]\n").unwrap();
+        let env: BTreeMap<String,String> = [("VPID_CFG_SPACE_TIME","1500"),("VPID_CFG_RULES__1__ENABLED","false"),("VPID_VERSION","0.1.1")]
+            .iter().map(|(k,v)| (k.to_string(),v.to_string())).collect();
+        let source = VpiConfigSource { path: dir.join("vpid.yml"), sets: vec!("short_time=300".to_string()) };
+        let (cfg,unknown) = source.load_env(&env).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(cfg.short_time, 300);
+        assert_eq!(cfg.space_time, 1500);
+        assert_eq!(cfg.rules.iter().map(|r| (r.name.as_str(),r.enabled)).collect::<Vec<_>>(), vec!(("base",true),("extra",false)));
+        assert_eq!(cfg.socket.acl.len(), 1);
+        assert_eq!(cfg.socket.acl[0].commands, vec!("status".to_string()));
+    }
+
+    #[test]
+    fn invalid_set() {
+        let path = std::env::temp_dir().join(format!("vpid-config-set-{}.yml",std::process::id()));
+        fs::write(&path, "short_time: 200\n").unwrap();
+        let source = VpiConfigSource { path: path.clone(), sets: vec!("short_time".to_string()) };
+        let res = source.load_checked();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(res, Err(Error::ConfigOverride { .. })));
+    }
+
+    #[test]
+    fn environment_only_warned() {
+        let cfg: VpiConfig = serde_yaml::from_str("shutdown_command: /nonexistent/poweroff\nfan: {mode: Pi, linear_min_temp: 60000, linear_max_temp: 50000, thermal_path: /nonexistent/temp}\n").unwrap();
+        assert!(cfg.validate().is_empty());
+        assert_eq!(cfg.warnings().len(), 2);
+        let cfg: VpiConfig = serde_yaml::from_str("fan: {mode: Linear, linear_min_temp: 60000, linear_max_temp: 50000}\n").unwrap();
+        assert_eq!(cfg.validate().len(), 1);
+    }
+}
@@ -2,23 +2,43 @@
 /// Handle rules and run scripts 
 /// 
 use vpi::{VpiStatus,VpiStats};
-use crate::config::{VpiConfig,VpiRule,VpiRuleType};
+use crate::config::{VpiConfig,VpiRule,VpiRuleType,VpiTrigger,VpiOnError,VpiMiniService};
 use crate::error::{Result,ResultExt,JsonError};
-use crate::cmd::{VpiCommand,exec_command_json};
+use crate::cmd::{VpiCommand,VpiOrigin,exec_command_json};
+use crate::events::{EventBus,VpiEvent};
+use crate::notify;
+use crate::keys::{self,KeyStore};
+use crate::fan::VpiFanConfig;
+use crate::audit::AuditRecord;
+use crate::sysinfo;
+use chrono::{Local,Datelike,Timelike};
 use rlua::{Lua, UserDataMethods,UserData};
-use serde_json::Value;
+use serde_json::{json,Value};
 use std::process::{Command,Child};
-use crossbeam_channel::{Sender,bounded};
+use crossbeam_channel::Sender;
 use std::thread::{JoinHandle};
 use std::time::{Instant,Duration};
 use std::thread;
 use std::sync::atomic::{AtomicBool,Ordering};
 use std::sync::{Arc,Weak};
+use std::collections::HashMap;
 // For force killing of pthreads
 //use std::os::unix::thread::JoinHandleExt;
 //use std::libc::pthread_cancel;
 
 
+/// Time to wait a stopped mini service before starting its replacement anyway
+const SERVICE_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// System variables of the rules, read once per sample period
+struct SysVars {
+    /// Time read
+    read: Instant,
+    /// SoC temperature in millicelsius
+    temp: Option<i32>,
+    info: sysinfo::SysInfo,
+}
+
 /// Info of Child process 
 struct ChildInfo {
     /// Sequence ID
@@ -41,18 +61,58 @@ struct LuaInfo {
     started: Instant,
     timeout: u32,
     control: Weak<AtomicBool>,
+    /// Mini service (not a rule script)
+    service: bool,
+}
+
+/// Trigger state of a rule
+#[derive(Default)]
+struct RuleState {
+    /// Time the condition became true
+    since: Option<Instant>,
+    /// Fired, or its match consumed, since the condition became true
+    fired: bool,
+    /// Last time fired
+    last_fire: Option<Instant>,
 }
 
 /// Engine object
 pub struct Engine<'a> {
-    pub cfg: &'a VpiConfig,
+    pub cfg: Arc<VpiConfig>,
     command_sender: &'a Sender<VpiCommand>,
+    events: &'a EventBus,
     childs: Vec<ChildInfo>,
     lchilds: Vec<LuaInfo>,
     seq: u32,
+    /// Times each rule matched
+    fires: HashMap<String,u64>,
+    /// Trigger state of the rules
+    states: HashMap<String,RuleState>,
+    /// Shell commands run by the rules, pending to be audited
+    audits: Vec<AuditRecord>,
+    /// Cached system variables
+    sys: Option<SysVars>,
+    /// Status inputs of the last status rules check
+    inputs: Option<Value>,
+    /// Keys changed since the last status rules check
+    keys_changed: bool,
+    /// Times the status rules were checked
+    checks: u64,
+    /// Mini services waiting their stopped instance to end, since the stop
+    restarts: Vec<(VpiMiniService,Instant)>,
 }
 
 
+/// Compile a Lua script or, if `expression`, a rule condition without running it
+pub fn check_lua(src: &str, expression: bool) -> std::result::Result<(),String> {
+    Lua::new().context(|ctx| {
+        if expression && ctx.load(&format!("return {}",src)).into_function().is_ok() {
+            return Ok(());
+        }
+        ctx.load(src).into_function().map(|_| ()).map_err(|e| e.to_string())
+    })
+}
+
 /// Serde -> Lua
 fn json_to_lua<'lua>(js:&serde_json::Value,ctx: &rlua::Context<'lua>) -> rlua::Result<rlua::Value<'lua>> {
     match js {
@@ -91,19 +151,20 @@ fn json_to_lua<'lua>(js:&serde_json::Value,ctx: &rlua::Context<'lua>) -> rlua::R
 /// Vpi object inside lua
 struct LuaVpi {
     sender: Sender<VpiCommand>,
+    origin: VpiOrigin,
 }
 
 impl LuaVpi {
     /// Create a new instance of vpi object inside lua
-    pub fn new(snd: &Sender<VpiCommand>) -> Self {
+    pub fn new(snd: &Sender<VpiCommand>,name: &str,service: bool) -> Self {
         Self {
-            sender: snd.clone()
+            sender: snd.clone(),
+            origin: if service { VpiOrigin::Service(name.to_string()) } else { VpiOrigin::Lua(name.to_string()) },
         }
     }
     ///
     fn exec(&self,cmd:&String) -> Result<Value> {
-        let (bc_sender,bc_receiver) = bounded::<String>(1); // Back channel for the response
-        exec_command_json(cmd, &self.sender, &bc_sender, &bc_receiver) // Return JSON 
+        exec_command_json(cmd, &self.sender, &self.origin) // Return JSON 
     }
 } //LuaVPi
 
@@ -116,6 +177,32 @@ impl UserData for LuaVpi {
             json_to_lua(&val, &ctx)
         });
 
+        methods.add_method("getkey", |ctx, lvpi, key:String| {
+            let val =lvpi.exec(&format!("getkey {}",key))?;
+            if val["result"].as_bool().unwrap_or(false) {
+                json_to_lua(&val["data"], &ctx)
+            } else {
+                Ok(rlua::Value::Nil)
+            }
+        });
+
+        methods.add_method("setkey", |_, lvpi, (key,value,ttl):(String,String,Option<u32>)| {
+            let cmd = match ttl {
+                Some(t) => format!("setkeyex {} {} {}",key,t,value),
+                None => format!("setkey {} {}",key,value),
+            };
+            Ok(lvpi.exec(&cmd)?["result"].as_bool().unwrap_or(false))
+        });
+
+        methods.add_method("delkey", |_, lvpi, key:String| {
+            Ok(lvpi.exec(&format!("delkey {}",key))?["result"].as_bool().unwrap_or(false))
+        });
+
+        methods.add_method("keys", |ctx, lvpi, prefix:Option<String>| {
+            let val =lvpi.exec(&format!("keys {}",prefix.unwrap_or_default()))?;
+            json_to_lua(&val["data"], &ctx)
+        });
+
         methods.add_method("sleep", |_,_lvpi,ms:u32| {
             thread::sleep(Duration::from_millis(ms as u64)); 
             Ok(())
@@ -140,19 +227,48 @@ impl UserData for LuaVpi {
 /// Engine implementation
 impl<'a> Engine<'a> {
     /// Create a new engine object
-    pub fn new(cfg:&'a VpiConfig,command_sender:&'a Sender<VpiCommand>) -> Self {
+    pub fn new(cfg:Arc<VpiConfig>,command_sender:&'a Sender<VpiCommand>,events:&'a EventBus) -> Self {
         Engine {
             cfg: cfg,
             command_sender: command_sender,
+            events,
             childs: vec!(),
             lchilds: vec!(),
             seq: 0,
+            fires: HashMap::new(),
+            states: HashMap::new(),
+            audits: vec!(),
+            sys: None,
+            inputs: None,
+            keys_changed: false,
+            checks: 0,
+            restarts: vec!(),
         }
     }
-    /// Fills lua context with exposed variables of current status and stats.
-    fn add_lua_variables(&self, stat: &VpiStatus, sts: &VpiStats,ctx: & rlua::Context) {
+    /// Period of the system variables, the fan `sample`
+    fn sample(&self) -> Duration {
+        match self.cfg.fan {
+            Some(ref fan) => fan.get_sample(),
+            None => VpiFanConfig::default().get_sample(),
+        }
+    }
+    /// Read the system variables if older than the sample period, true if read
+    fn refresh_sys(&mut self) -> bool {
+        if self.sys.as_ref().map(|s| s.read.elapsed() < self.sample()).unwrap_or(false) {
+            return false;
+        }
+        self.sys=Some(SysVars { read: Instant::now(), temp: self.cfg.get_temp(), info: sysinfo::SysInfo::read() });
+        true
+    }
+    /// Fills lua context with exposed variables of current status, stats and key store.
+    fn add_lua_variables(&self, stat: &VpiStatus, sts: &VpiStats, keys: &KeyStore, duty: u8, ctx: & rlua::Context) {
         let globs=ctx.globals();
         
+        let keys=keys.values();
+        if let Ok(tbl) = ctx.create_table_from(keys.iter().map(|(k,v)| (k.as_str(),v.as_str()))) {
+            let _=globs.set("keys",tbl);
+        }
+        
         let _=globs.set("has_click",stat.has_click);
         let _=globs.set("has_rpm",stat.has_rpm);
         let _=globs.set("has_irq",stat.has_irq);
@@ -169,17 +285,56 @@ impl<'a> Engine<'a> {
         let _=globs.set("vpi_recovers",sts.recovers);
         let _=globs.set("vpi_status_checks",sts.status_checks);
         let _=globs.set("vpi_retries",sts.retries);
+
+        let _=globs.set("is_wdg_enabled",stat.is_wdg_enabled);
+        let _=globs.set("is_wake_enabled",stat.is_wake_enabled);
+        let _=globs.set("is_wake_irq_enabled",stat.is_wake_irq_enabled);
+        let _=globs.set("recover_type",stat.recover_type);
+
+        let _=globs.set("fan_duty",duty);
+        let _=globs.set("fan_target",self.cfg.fan.as_ref().and_then(|f| f.get_target()));
+
+        if let Some(ref sys) = self.sys {
+            let _=globs.set("temp",sys.temp);
+            if let Some((l1,l5,l15)) = sys.info.load {
+                let _=globs.set("load1",l1);
+                let _=globs.set("load5",l5);
+                let _=globs.set("load15",l15);
+            }
+            if let Some((total,available)) = sys.info.memory {
+                let _=globs.set("mem_total",total);
+                let _=globs.set("mem_available",available);
+                let _=globs.set("mem_used",sysinfo::used_pct(total,available));
+            }
+            if let Some((total,free)) = sys.info.disk {
+                let _=globs.set("disk_total",total);
+                let _=globs.set("disk_free",free);
+                let _=globs.set("disk_used",sysinfo::used_pct(total,free));
+            }
+            let _=globs.set("uptime",sys.info.uptime);
+            if let Some(bits) = sys.info.throttled {
+                let _=globs.set("throttled",bits);
+                for (name,on) in sysinfo::throttled_flags(bits) {
+                    let _=globs.set(name,on);
+                }
+            }
+        }
+
+        let now=Local::now();
+        let _=globs.set("hour",now.hour());
+        let _=globs.set("minute",now.minute());
+        let _=globs.set("weekday",now.weekday().number_from_monday());
         
     }
  
     /// Run a lua script in a independent thread
-    fn run_lua(&mut self,name:&str,script:&str,timeout: u32) {
+    fn run_lua(&mut self,name:&str,script:&str,timeout: u32,service: bool) {
         
         let cancel_control=Arc::new(AtomicBool::new(false));    // Strong reference with flag for cancellation will be moved to lua thread
         let downgraded_cancel=Arc::downgrade(&cancel_control); // Weak version to control the flag for main thread
         let scr=String::from(script); // Copy script to be used in the thread
         let names=String::from(name); // Copy to be used in thread 
-        let luavpi= LuaVpi::new(self.command_sender); // Lua vpi object for this execution
+        let luavpi= LuaVpi::new(self.command_sender,name,service); // Lua vpi object for this execution
         
         
         let handle= thread::spawn(move || {
@@ -189,6 +344,7 @@ impl<'a> Engine<'a> {
             lua.context(|lua_ctx| {    
                 let globs=lua_ctx.globals();
                 let _= globs.set("vpi",luavpi); // move vpi object to the thread
+                let _= globs.set("vpi_namespace",keys::namespace(&names)); // key store namespace
     
                 let cancel_fnr=lua_ctx.create_function( move |_, () | {
                     Ok((*cancel_control).load(Ordering::Relaxed))
@@ -212,26 +368,31 @@ impl<'a> Engine<'a> {
             timeout: timeout,
             control: downgraded_cancel,
             handle: handle,
+            service,
         };
         self.seq+=1;
         self.lchilds.push(job);
 
     }
-    /// Run a shell command
-    fn run_shell(&mut self,name:&str,cmd:&str,args:&str,asyncr: bool,timeout: u32) {
-        if cmd == "" { return; }
+    /// Run a shell command, returns the result for the audit
+    fn run_shell(&mut self,name:&str,cmd:&str,args:&str,asyncr: bool,timeout: u32) -> String {
+        if cmd.is_empty() { return "no command".to_string(); }
         let cmds : Vec<&str>= cmd.split_whitespace().collect();
         let mut c=Command::new(cmds[0]);
         if cmds.len() >= 2 { c.args(cmds[1..].iter()); }
         if args != "" { c.arg(args) ;}
         trace!("Shell command:'{:?}'",c);
         info!("Starting shell process id: {}-[{}] async:{} , timeout:{}",self.seq,name,asyncr,timeout);
+        let result;
         if asyncr {
             let child_result=c.spawn();
             if let Ok(child) = child_result {
+                result=format!("started pid {}",child.id());
                 self.childs.push(ChildInfo { id: self.seq, child: child, name: name.to_string(), started: std::time::Instant::now(), timeout: timeout});
             } else {
-                error!("Shell process {} failed [{}]",name,child_result.unwrap_err());
+                let e=child_result.unwrap_err();
+                error!("Shell process {} failed [{}]",name,e);
+                result=format!("failed {}",e);
             }
         } else {
             let out_result=c.output();
@@ -242,25 +403,86 @@ impl<'a> Engine<'a> {
                    } else {
                     warn!("Shell process finished with error {}-[{}] exit code:{}",self.seq, name, code);
                    }
+                result=format!("exit code {}",code);
             } else {
-                error!("Shell process {} failed [{}]",name,out_result.unwrap_err());
+                let e=out_result.unwrap_err();
+                error!("Shell process {} failed [{}]",name,e);
+                result=format!("failed {}",e);
             }
         }
         self.seq+=1;
+        result
+    }
+    /// Run a shell command of a rule recording it for the audit
+    fn run_audited_shell(&mut self,rule: &VpiRule,cmd:&str,args:&str) {
+        let started=Instant::now();
+        let shell=rule.kind == VpiRuleType::Shell; // Shutdown & reboot run synchronously
+        let result=self.run_shell(rule.name.as_str(),cmd,args,shell && rule.asyncr,if shell { rule.timeout } else { 0 });
+        let text=if args.is_empty() { cmd.to_string() } else { format!("{} {}",cmd,args) };
+        self.audits.push(AuditRecord { origin: VpiOrigin::Rule(rule.name.clone()), text, result, latency: started.elapsed() });
+    }
+    /// Shell commands run by the rules since the last call, to be audited
+    pub fn take_audits(&mut self) -> Vec<AuditRecord> {
+        std::mem::take(&mut self.audits)
+    }
+    /// Send the commands of a rule in a thread, the serve loop answers them
+    fn run_commands(&mut self,rule: &VpiRule) {
+        let steps=rule.commands.clone();
+        let sender=self.command_sender.clone();
+        let origin=VpiOrigin::Rule(rule.name.clone());
+        let (id,name,delay,on_error,timeout)=(self.seq,rule.name.clone(),rule.delay,rule.on_error,rule.timeout);
+        info!("Starting command sequence {}-[{}] steps:{} timeout:{}",id,name,steps.len(),timeout);
+        thread::spawn(move || {
+            let started=Instant::now();
+            for (i,step) in steps.iter().enumerate() {
+                if i > 0 && delay > 0 {
+                    thread::sleep(Duration::from_millis(delay as u64));
+                }
+                if timeout > 0 && started.elapsed() > Duration::from_secs(timeout as u64) {
+                    warn!("Command sequence {}-[{}] timeout before step '{}'",id,name,step);
+                    return;
+                }
+                if let Some(ms) = step.strip_prefix("wait ") {
+                    thread::sleep(Duration::from_millis(ms.trim().parse::<u64>().unwrap_or(0)));
+                    continue;
+                }
+                let failure = match exec_command_json(step,&sender,&origin) {
+                    Ok(res) if res["result"].as_bool() == Some(true) => None,
+                    Ok(res) => Some(res["data"].to_string()),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(e) = failure {
+                    warn!("Command sequence {}-[{}] step '{}' failed: {}",id,name,step,e);
+                    if on_error == VpiOnError::Stop {
+                        return;
+                    }
+                }
+            }
+            info!("Command sequence {}-[{}] finished",id,name);
+        });
+        self.seq+=1;
     }
     /// Run Rule
-    fn run_rule(&mut self,rule :&VpiRule)  {
+    fn run_rule(&mut self,rule :&VpiRule, stat: &VpiStatus, sts: &VpiStats)  {
+        let cfg=self.cfg.clone();
         match rule.kind {
-                VpiRuleType::Reboot   => self.run_shell(rule.name.as_str(),self.cfg.reboot_command.as_str(),"",false,0),
-                VpiRuleType::Shutdown => self.run_shell(rule.name.as_str(),self.cfg.shutdown_command.as_str(),"",false,0),
+                VpiRuleType::Reboot   => self.run_audited_shell(rule,cfg.reboot_command.as_str(),""),
+                VpiRuleType::Shutdown => self.run_audited_shell(rule,cfg.shutdown_command.as_str(),""),
                 VpiRuleType::Lua      => {
                     if let Some(script) = &rule.script {
-                        self.run_lua(rule.name.as_str(), script, rule.timeout);
+                        self.run_lua(rule.name.as_str(), script, rule.timeout, false);
                     }
                 },
                 VpiRuleType::Shell    => {
                     if let Some(script) = &rule.script {
-                        self.run_shell(rule.name.as_str(),self.cfg.shell.as_str(),script,rule.asyncr,rule.timeout);
+                        self.run_audited_shell(rule,cfg.shell.as_str(),script);
+                    }
+                },
+                VpiRuleType::Commands => self.run_commands(rule),
+                VpiRuleType::Webhook  => {
+                    if let Some(hook) = &rule.webhook {
+                        let temp=cfg.get_temp().map(|t| t as f32/1000.0);
+                        hook.post(&rule.name, notify::payload(json!({ "rule": rule.name }), temp, json!(stat), json!(sts)));
                     }
                 },
                 VpiRuleType::Nop      => { }
@@ -269,45 +491,166 @@ impl<'a> Engine<'a> {
     /// Starts all configured mini services
     pub fn start_miniservices(&mut self) -> Result<u32> {
         let mut done = 0;
-        let total= self.cfg.services.len();
+        let cfg=self.cfg.clone();
+        let total= cfg.services.len();
         info!("Starting miniservices total:{}",total);
-        for ser in self.cfg.services.iter() {
+        for ser in cfg.services.iter() {
             info!("Launching lua mini service [{}] {} of {}",ser.name,done,total);
-            self.run_lua(ser.name.as_str(), ser.script.as_str(), 0);
+            self.run_lua(ser.name.as_str(), ser.script.as_str(), 0, true);
             done+=1;
         }
         Ok(done)
     }
-    /// check and run rules
-    pub fn run_rules(&mut self,stat: &VpiStatus, sts: &VpiStats) -> Result<()> {
+    /// Swap the configuration. New rules apply from the next check, only the added,
+    /// removed or edited mini services are restarted and running jobs are kept. Edited
+    /// services are cancelled and started again by `test_lua_childs` once ended.
+    pub fn reconfigure(&mut self,cfg: Arc<VpiConfig>) {
+        let old=std::mem::replace(&mut self.cfg,cfg.clone());
+        for ser in old.services.iter().filter(|s| !cfg.services.contains(s)) {
+            for job in self.lchilds.iter().filter(|j| j.service && j.name==ser.name) {
+                if let Some(cancel) = job.control.upgrade() {
+                    info!("Stopping lua mini service {}-[{}]",job.id,job.name);
+                    (*cancel).store(true,Ordering::Relaxed);
+                }
+            }
+        }
+        self.restarts.retain(|(s,_)| cfg.services.contains(s));
+        let now=Instant::now();
+        for ser in cfg.services.iter().filter(|s| !old.services.contains(s)) {
+            self.restarts.retain(|(s,_)| s.name!=ser.name);
+            self.restarts.push((ser.clone(),now));
+        }
+        self.start_restarts(); // Added services start now, the others once stopped
+        for (name,fires) in self.fires.iter().filter(|(name,_)| !cfg.rules.iter().any(|r| &r.name==*name)) {
+            info!("Rule [{}] removed or renamed, its {} fires are dropped from the metrics",name,fires);
+        }
+        self.fires.retain(|name,_| cfg.rules.iter().any(|r| &r.name==name));
+        self.states.retain(|name,_| cfg.rules.iter().any(|r| &r.name==name));
+        self.sys=None; // Check the new rules & thermal path on the next poll
+        self.inputs=None;
+    }
+    /// Start the mini services waiting for restart whose previous instance ended,
+    /// or anyway if it did not stop in `SERVICE_STOP_TIMEOUT`
+    fn start_restarts(&mut self) {
+        let mut waiting=vec!();
+        for (ser,since) in std::mem::take(&mut self.restarts) {
+            let running=self.lchilds.iter().any(|j| j.service && j.name==ser.name && j.control.upgrade().is_some());
+            if running && since.elapsed() < SERVICE_STOP_TIMEOUT {
+                waiting.push((ser,since));
+                continue;
+            }
+            if running {
+                warn!("Lua mini service [{}] did not stop in {} s, starting the new one anyway",ser.name,SERVICE_STOP_TIMEOUT.as_secs());
+            }
+            info!("Launching lua mini service [{}]",ser.name);
+            self.run_lua(ser.name.as_str(), ser.script.as_str(), 0, true);
+        }
+        self.restarts=waiting;
+    }
+    /// check and run the status rules, called on every poll. `duty` is the fan duty.
+    /// Rules are checked only if the status, stats, duty, keys or system variables
+    /// changed, or while a rule waits its debounce or a level rule is true.
+    pub fn run_rules(&mut self,stat: &VpiStatus, sts: &VpiStats, keys: &KeyStore, duty: u8) -> Result<()> {
+        if !self.cfg.rules.iter().any(|r| r.enabled && r.on_key.is_none()) {
+            return Ok(());
+        }
+        let inputs=json!([stat, sts.retries, sts.recovers, sts.i2c_errors, sts.crc_errors, duty]);
+        let changed=self.refresh_sys() || self.keys_changed || self.inputs.as_ref() != Some(&inputs);
+        let pending=self.cfg.rules.iter().filter(|r| r.enabled && r.on_key.is_none()).any(|r| match self.states.get(&r.name) {
+            Some(state) => state.since.is_some() && (!state.fired || r.trigger == VpiTrigger::Level),
+            None => false,
+        });
+        if !changed && !pending {
+            trace!("Rule inputs unchanged");
+            return Ok(());
+        }
+        self.inputs=Some(inputs);
+        self.keys_changed=false;
+        self.checks+=1;
+        self.eval_rules(stat,sts,keys,duty,None)
+    }
+    /// check and run the `on_key` rules watching a changed key, `value` is None if deleted
+    pub fn run_key_rules(&mut self,key: &str, value: Option<&str>, stat: &VpiStatus, sts: &VpiStats, keys: &KeyStore, duty: u8) -> Result<()> {
+        self.keys_changed=true;
+        self.eval_rules(stat,sts,keys,duty,Some((key,value)))
+    }
+    /// Evaluate status rules or, on a key change, the rules watching the key.
+    /// Enabled rules are checked by priority. Status rules fire after their condition
+    /// is true for `debounce` ms, once (edge) or on every check (level). Fires within
+    /// `cooldown` are skipped and, once a rule without `continue` fires, the matches
+    /// of the next rules are consumed without firing.
+    fn eval_rules(&mut self,stat: &VpiStatus, sts: &VpiStats, keys: &KeyStore, duty: u8, change: Option<(&str,Option<&str>)>) -> Result<()> {
+        let cfg=self.cfg.clone();
+        let mut rules: Vec<&VpiRule> = cfg.rules.iter().filter(|r| r.enabled && match (change, &r.on_key) {
+            (None, None) => true,
+            (Some((key,_)), Some(watch)) => watch == key || (watch.ends_with('*') && key.starts_with(watch.trim_end_matches('*'))),
+            _ => false,
+        }).collect();
+        rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
 
-        if self.cfg.rules.len() == 0 { 
+        if rules.is_empty() {
             trace!("No rules to execute");
             return Ok(()); 
         } // Ignore if rules empty
+        self.refresh_sys();
     
         let lua = Lua::new();
         lua.context(|lua_ctx| {
-            self.add_lua_variables(stat,sts,&lua_ctx); // Fill the context
-            for rule in &self.cfg.rules {
-                match lua_ctx.load(rule.when.as_str()).eval::<bool>() {
-                    Ok(res) => {
-                        if res {
-                            info!("Rule [{}] matched!",rule.name);
-                            self.run_rule(&rule);
-                            break;
-                        }
-                    },
-                    Err(e) => warn!("can't evaluate rule [{}]:{}",rule.when,e)
+            self.add_lua_variables(stat,sts,keys,duty,&lua_ctx); // Fill the context
+            if let Some((key,value)) = change {
+                let _=lua_ctx.globals().set("key",key);
+                let _=lua_ctx.globals().set("value",value);
+            }
+            let now=Instant::now();
+            let mut stopped=false;
+            for rule in rules {
+                let when = if change.is_some() && rule.when.trim().is_empty() { "true" } else { rule.when.as_str() };
+                let res = match lua_ctx.load(when).eval::<bool>() {
+                    Ok(res) => res,
+                    Err(e) => { warn!("can't evaluate rule [{}]:{}",rule.when,e); false }
+                };
+                let state=self.states.entry(rule.name.clone()).or_default();
+                if !res {
+                    if change.is_none() { state.since=None; state.fired=false; }
+                    continue;
+                }
+                if change.is_none() {
+                    let since=*state.since.get_or_insert(now);
+                    if now.duration_since(since) < Duration::from_millis(rule.debounce as u64) { continue; }
+                    if rule.trigger == VpiTrigger::Edge && state.fired { continue; }
+                }
+                state.fired=true;
+                if stopped {
+                    debug!("Rule [{}] matched after a rule without continue, skipped",rule.name);
+                    continue;
                 }
+                if state.last_fire.map(|t| now.duration_since(t) < Duration::from_secs(rule.cooldown as u64)).unwrap_or(false) {
+                    debug!("Rule [{}] matched in cooldown, skipped",rule.name);
+                    continue;
+                }
+                state.last_fire=Some(now);
+                info!("Rule [{}] matched!",rule.name);
+                *self.fires.entry(rule.name.clone()).or_insert(0)+=1;
+                self.events.publish(VpiEvent::Rule { name: rule.name.clone(), kind: format!("{:?}",rule.kind) });
+                self.run_rule(rule,stat,sts);
+                stopped = !rule.cont;
             }
 
         });
         Ok(())
     }
+    /// Counters of the engine: running jobs & rule matches
+    pub fn metrics(&self) -> Value {
+        json!({
+            "lua_jobs": self.lchilds.len(),
+            "shell_jobs": self.childs.len(),
+            "rule_fires": self.fires,
+            "rule_checks": self.checks,
+        })
+    }
     /// Test childs
     pub fn test_childs(&mut self,force_kill:bool) {
-        for pos in 0..self.childs.len() {
+        for pos in (0..self.childs.len()).rev() { // Backwards as finished jobs are removed
             let e=&mut self.childs[pos];
             if let Ok(Some(status)) = e.child.try_wait() {
                 let code = status.code().unwrap_or(255);
@@ -350,9 +693,12 @@ impl<'a> Engine<'a> {
                 }
             }
         }
-        for i in to_join.iter() {
+        for i in to_join.iter().rev() { // Backwards to keep the positions valid
             let _= self.lchilds.remove(*i).handle.join();
         }
+        if !force_kill {
+            self.start_restarts();
+        }
     }
 
 }
@@ -380,3 +726,135 @@ impl<'a> Drop for Engine<'a> {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VpiMiniService;
+    use crate::keys::VpiKeysConfig;
+    use vpi::Vpi;
+    use crossbeam_channel::unbounded;
+
+    fn service(name: &str, script: &str) -> VpiMiniService {
+        VpiMiniService { name: name.to_string(), script: script.to_string() }
+    }
+
+    #[test]
+    fn reconfigure_restarts_stopped_services() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let looping = "while not vpi_test_cancel() do vpi:sleep(10) end return 0";
+        let cfg = VpiConfig { services: vec!(service("a",looping), service("b",looping)), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        engine.start_miniservices().unwrap();
+        let old_ids: Vec<u32> = engine.lchilds.iter().map(|j| j.id).collect();
+        engine.fires.insert("gone".to_string(), 3);
+        let edited = "while not vpi_test_cancel() do vpi:sleep(20) end return 1";
+        let cfg = VpiConfig { services: vec!(service("a",looping), service("b",edited)), ..Default::default() };
+        let started = Instant::now();
+        engine.reconfigure(Arc::new(cfg));
+        assert!(started.elapsed() < Duration::from_millis(10)); // Not waiting the old b
+        assert!(engine.fires.is_empty());
+        assert_eq!(engine.restarts.len(), 1);
+        // The old b ends & is joined by the poll before the new one starts
+        while !engine.restarts.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+            engine.test_lua_childs(false);
+        }
+        let jobs: Vec<(&str,u32)> = engine.lchilds.iter().map(|j| (j.name.as_str(),j.id)).collect();
+        assert_eq!(jobs, vec!(("a",old_ids[0]), ("b",2)));
+        engine.test_lua_childs(true);
+        while engine.lchilds.iter().any(|j| j.control.upgrade().is_some()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        engine.test_lua_childs(false);
+    }
+
+    fn rule(name: &str, when: &str, trigger: VpiTrigger) -> VpiRule {
+        VpiRule { name: name.to_string(), when: when.to_string(), trigger, ..Default::default() }
+    }
+
+    fn short(clicks: i32) -> VpiStatus {
+        VpiStatus { pwr_short: clicks, ..Default::default() }
+    }
+
+    /// Check the status rules with `clicks`, returns the fires of `name` & the checks
+    fn check(engine: &mut Engine, keys: &KeyStore, clicks: i32, name: &str) -> (u64,u64) {
+        engine.run_rules(&short(clicks), &Vpi::new(None,false).get_stats(), keys, 0).unwrap();
+        (engine.fires.get(name).copied().unwrap_or(0), engine.checks)
+    }
+
+    fn memory_keys() -> KeyStore {
+        KeyStore::open(&VpiKeysConfig { file: None, ..Default::default() })
+    }
+
+    #[test]
+    fn edge_fires_once() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let cfg = VpiConfig { rules: vec!(rule("two","short == 2",VpiTrigger::Edge)), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        let keys = memory_keys();
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 1);
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 1);
+        assert_eq!(check(&mut engine,&keys,0,"two").0, 1);
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 2);
+    }
+
+    #[test]
+    fn level_fires_while_true() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let cfg = VpiConfig { rules: vec!(rule("two","short == 2",VpiTrigger::Level)), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        let keys = memory_keys();
+        assert_eq!(check(&mut engine,&keys,2,"two"), (1,1));
+        assert_eq!(check(&mut engine,&keys,2,"two"), (2,2));
+        assert_eq!(check(&mut engine,&keys,0,"two"), (2,3));
+        // False & unchanged inputs are not checked again
+        assert_eq!(check(&mut engine,&keys,0,"two"), (2,3));
+    }
+
+    #[test]
+    fn cooldown_skips_fires() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let cfg = VpiConfig { rules: vec!(VpiRule { cooldown: 1, ..rule("two","short == 2",VpiTrigger::Level) }), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        let keys = memory_keys();
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 1);
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 1);
+        thread::sleep(Duration::from_millis(1050));
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 2);
+    }
+
+    #[test]
+    fn debounce_waits_true() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let cfg = VpiConfig { rules: vec!(VpiRule { debounce: 100, ..rule("two","short == 2",VpiTrigger::Edge) }), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        let keys = memory_keys();
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 0);
+        assert_eq!(check(&mut engine,&keys,0,"two").0, 0);
+        assert_eq!(check(&mut engine,&keys,2,"two").0, 0);
+        thread::sleep(Duration::from_millis(120));
+        // Pending debounce is checked with unchanged inputs
+        assert_eq!(check(&mut engine,&keys,2,"two"), (1,4));
+        assert_eq!(check(&mut engine,&keys,2,"two"), (1,4));
+    }
+
+    #[test]
+    fn key_changes_recheck() {
+        let (sender,_receiver) = unbounded();
+        let events = EventBus::new();
+        let cfg = VpiConfig { rules: vec!(rule("mode","keys.mode == 'eco'",VpiTrigger::Edge)), ..Default::default() };
+        let mut engine = Engine::new(Arc::new(cfg), &sender, &events);
+        let mut keys = memory_keys();
+        assert_eq!(check(&mut engine,&keys,0,"mode"), (0,1));
+        assert_eq!(check(&mut engine,&keys,0,"mode"), (0,1));
+        keys.set("mode","eco",None).unwrap();
+        engine.run_key_rules("mode",Some("eco"),&short(0),&Vpi::new(None,false).get_stats(),&keys,0).unwrap();
+        assert_eq!(check(&mut engine,&keys,0,"mode"), (1,2));
+    }
+}
@@ -9,18 +9,33 @@ use crossbeam_channel::{RecvTimeoutError,SendTimeoutError};
 pub enum Error {
     #[snafu(display("Couldn't read file {}: {}", filename.display(), source ))]
     ReadConfig { filename: PathBuf, source: std::io::Error },
-    #[snafu(display("Could not save config to {}: {}", filename.display(), source ))]
+    #[snafu(display("Invalid config include pattern {}: {}", pattern, source ))]
+    ConfigInclude { pattern: String, source: glob::PatternError },
+    #[snafu(display("Invalid config override '{}', expected key=value", set ))]
+    ConfigOverride { set: String },
+    #[snafu(display("Couldn't parse config file {}: {}", filename.display(), source ))]
     ParseConfig { filename: PathBuf, source: serde_yaml::Error },
+    #[snafu(display("Invalid config file {}: {}", filename.display(), problems.join("; ") ))]
+    InvalidConfig { filename: PathBuf, problems: Vec<String> },
     #[snafu(display("Couldn't communicate with vpi board on {} addr {}: {}",dev.display(),addr ,source ))]
     I2cOpen { dev: PathBuf, addr: u16 , source: vpi::Error },
     #[snafu(display("Couldn't open socket {}: {}", sock.display(), source ))]
     SockBind { sock: PathBuf, source: std::io::Error },
+    #[snafu(display("Couldn't configure socket {}: {}", sock.display(), msg ))]
+    SockConfig { sock: PathBuf, msg: String },
+    #[snafu(display("Couldn't start HTTP server on {}: {}", addr, msg ))]
+    HttpBind { addr: String, msg: String },
+    #[snafu(display("Couldn't watch {}: {}", path.display(), source ))]
+    WatchInit { path: PathBuf, source: nix::Error },
     #[snafu(display("Could not configure vpi board: {}", source ))]
     VpiConfigureError { source: vpi::Error },
     #[snafu(display("Command parse failed, Unkwnon command vpi: {}", cmd ))]
     CommandParse { cmd: String },
     #[snafu(display("Command could not be sent: {}", source ))]
-    CommandSend { source: SendTimeoutError<VpiCommand> },
+    CommandSend {
+        #[snafu(source(from(SendTimeoutError<VpiCommand>, Box::new)))]
+        source: Box<SendTimeoutError<VpiCommand>>
+    },
     #[snafu(display("Command response failed or timedout: {}", source ))]
     CommandRecv { source: RecvTimeoutError },
     #[snafu(display("Invalid JSON: {}", source ))]
@@ -0,0 +1,104 @@
+//! Event bus
+//! Events detected by the daemon (monitor loop, fan regulation, rules, key storage)
+//! are published to the subscribers, e.g. socket sessions after `subscribe`.
+//! A slow subscriber loses events instead of blocking the daemon.
+
+use serde::Serialize;
+use std::sync::{Arc,Mutex};
+use crossbeam_channel::{Sender,Receiver,TrySendError,bounded};
+use vpi::{VpiStatus,VpiStats};
+
+/// Events queued per subscriber before dropping
+const EVENT_QUEUE: usize = 64;
+
+/// Names of the events, used by subscription filters
+pub const EVENT_NAMES: [&str; 8] = ["button","irq","rpm","recover","crc_resync","fan","rule","key"];
+
+/// Events of the daemon. Serialized as `{"event":"<name>",...}`
+#[derive(Debug,Clone,Serialize)]
+#[serde(tag="event",rename_all="snake_case")]
+pub enum VpiEvent {
+    /// Button clicks with the counts of short & long clicks
+    Button { pwr_short: i32, pwr_long: i32, aux_short: i32, aux_long: i32 },
+    /// IRQ input activated
+    Irq,
+    /// New fan speed read
+    Rpm { rpm: i32 },
+    /// I2C connection recovered
+    Recover { recover_type: u8, recovers: u32 },
+    /// Board configuration resynchronized after a CRC mismatch
+    CrcResync { crc: u8, crc_errors: u32 },
+    /// Fan duty changed
+    Fan { duty: u8 },
+    /// Rule matched and launched
+    Rule { name: String, kind: String },
+    /// Key storage changed, value None if deleted or expired
+    Key { key: String, value: Option<String> },
+}
+
+impl VpiEvent {
+    /// Name of the event as used by filters
+    pub fn name(&self) -> &'static str {
+        match self {
+            VpiEvent::Button {..}    => "button",
+            VpiEvent::Irq            => "irq",
+            VpiEvent::Rpm {..}       => "rpm",
+            VpiEvent::Recover {..}   => "recover",
+            VpiEvent::CrcResync {..} => "crc_resync",
+            VpiEvent::Fan {..}       => "fan",
+            VpiEvent::Rule {..}      => "rule",
+            VpiEvent::Key {..}       => "key",
+        }
+    }
+    /// Events of a new status of the monitor loop
+    pub fn from_status(st: &VpiStatus, sts: &VpiStats, last_rpm: i32, last_sts: &VpiStats) -> Vec<VpiEvent> {
+        let mut evs = vec!();
+        if st.has_click {
+            evs.push(VpiEvent::Button { pwr_short: st.pwr_short, pwr_long: st.pwr_long, aux_short: st.aux_short, aux_long: st.aux_long });
+        }
+        if st.has_irq {
+            evs.push(VpiEvent::Irq);
+        }
+        if st.has_rpm && st.rpm != last_rpm {
+            evs.push(VpiEvent::Rpm { rpm: st.rpm });
+        }
+        if sts.recovers > last_sts.recovers {
+            evs.push(VpiEvent::Recover { recover_type: st.recover_type, recovers: sts.recovers });
+        }
+        if sts.crc_errors > last_sts.crc_errors {
+            evs.push(VpiEvent::CrcResync { crc: st.crc, crc_errors: sts.crc_errors });
+        }
+        evs
+    }
+}
+
+/// Publisher of events to many subscribers
+#[derive(Debug,Clone,Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<VpiEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// New subscriber. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<VpiEvent> {
+        let (sender,receiver) = bounded::<VpiEvent>(EVENT_QUEUE);
+        if let Ok(mut subs) = self.subscribers.lock() {
+            subs.push(sender);
+        }
+        receiver
+    }
+    /// Send the event to all subscribers
+    pub fn publish(&self, ev: VpiEvent) {
+        trace!("Event {:?}",ev);
+        if let Ok(mut subs) = self.subscribers.lock() {
+            subs.retain(|s| match s.try_send(ev.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => { warn!("Event subscriber queue full, {} event lost",ev.name()); true },
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+    }
+}
@@ -1,35 +1,58 @@
 //! Fan control module
 
-use serde::Deserialize;
+use serde::{Deserialize,Serialize};
 use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
 use std::fs::read_to_string;
 use std::path::Path;
+use std::time::Duration;
+use serde_json::{json,Value};
 
-#[derive(Copy,Clone,Eq,PartialEq,Debug,Deserialize)]
+/// Fan modes of operation
+#[derive(Copy,Clone,Eq,PartialEq,Debug,Deserialize,Serialize,JsonSchema)]
 pub enum VpiFanMode {
+    /// Always off
     Off,
+    /// Always full speed
     On,
+    /// Constant speed `custom_value`
     Custom,
+    /// PI regulation to `pi_desired_temp`
     Pi,
+    /// Speed linear to the temperature between `linear_min_temp` and `linear_max_temp`
     Linear
 }
 
 //#[derive(DeserializePiecewiseDefault,PartialEq,Eq,Debug)]
-#[derive(DeserializePiecewiseDefault,Debug,Clone,PartialEq)]
+/// Fan control configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
 pub struct VpiFanConfig {
+     /// Fan pins: 2, 3 or 4
      pins: u8,
+     /// Divisor for rpm computation
      divisor: u8,
+     /// Period of the regulation in seconds
      sample: u32,
+     /// PWM frequency in Hz, by default based on the pins
      pwm_freq: Option<u16>,
+     /// Temperature file in millicelsius
      thermal_path: String,
+     /// Mode of operation
      mode: VpiFanMode,
+     /// Linear mode: full speed temperature in millicelsius
      linear_max_temp: i32,
+     /// Linear mode: stop temperature in millicelsius
      linear_min_temp: i32,
+     /// Pi mode: desired temperature in millicelsius
      pi_desired_temp: i32,
+     /// Custom mode: speed 0-255
      custom_value: u8,
+     /// Pi mode: proportional gain
      kp: f32,
+     /// Pi mode: integral gain
      ki: f32,
-     //#[serde(skip)]
+     #[serde(skip)]
      pi_sum: i64,
 }
 
@@ -37,7 +60,7 @@ impl Default for VpiFanConfig {
     fn default() -> Self {
         VpiFanConfig {
             pins: 2,
-            sample: 5,
+            sample: 3,
             divisor: 2,
             pwm_freq: None,
             thermal_path: String::from("/sys/class/thermal/thermal_zone0/temp"),
@@ -55,6 +78,10 @@ impl Default for VpiFanConfig {
 }
 
 impl VpiFanConfig {
+    /// Period of the fan regulation, at least 1 second
+    pub fn get_sample(&self) -> Duration {
+        Duration::from_secs(self.sample.max(1) as u64)
+    }
     /// Get recommended pwm frequency
     pub fn get_pwmfreq(&self) -> u16 {
         if let Some(p) = self.pwm_freq {
@@ -77,6 +104,10 @@ impl VpiFanConfig {
             self.divisor
         }
     }
+    /// Keep the regulation state of a previous configuration
+    pub fn keep_state(&mut self,old:&VpiFanConfig) {
+        self.pi_sum=old.pi_sum;
+    }
     /// Force fan regulation to fixed value.
     pub fn set_fan(&mut self,val:u8) {
         self.mode = VpiFanMode::Custom;
@@ -91,7 +122,9 @@ impl VpiFanConfig {
             VpiFanMode::Custom => self.custom_value,
             VpiFanMode::Linear => {
                 let t=self.get_temp();
-                if t != -1 {
+                if t != -1 && self.linear_max_temp <= self.linear_min_temp { // No range, on/off at max
+                    if t >= self.linear_max_temp { 255u8 } else { 0u8 }
+                } else if t != -1 {
                     let mut scaled_t= 256 * (t-self.linear_min_temp);
                     scaled_t/= self.linear_max_temp - self.linear_min_temp;
                     match scaled_t {
@@ -120,6 +153,68 @@ impl VpiFanConfig {
             }
         }
     }
+    /// Thermal state as JSON with temperature in ºC (null if not available) and fan duty
+    pub fn thermal(fan: &Option<VpiFanConfig>, duty: u8) -> Value {
+        match fan {
+            Some(f) => {
+                let t=f.get_temp();
+                json!({
+                    "temp": if t == -1 { Value::Null } else { json!(t as f32/1000.0) },
+                    "duty": duty,
+                    "mode": format!("{:?}",f.mode),
+                    "target": if f.mode == VpiFanMode::Pi { json!(f.pi_desired_temp as f32/1000.0) } else { Value::Null },
+                    "pi_sum": f.pi_sum,
+                })
+            },
+            None => {
+                let t=VpiFanConfig::default().get_temp();
+                json!({
+                    "temp": if t == -1 { Value::Null } else { json!(t as f32/1000.0) },
+                    "duty": duty,
+                    "mode": Value::Null,
+                    "target": Value::Null,
+                    "pi_sum": 0,
+                })
+            }
+        }
+    }
+    /// Problems of the configuration
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors=vec!();
+        if let Some(p) = self.pwm_freq {
+            if !(2..=62500).contains(&p) {
+                errors.push(format!("fan.pwm_freq {} out of range 2-62500",p));
+            }
+        }
+        if self.sample == 0 {
+            errors.push("fan.sample must be > 0 seconds".to_string());
+        }
+        if self.divisor == 0 {
+            errors.push("fan.divisor must be > 0".to_string());
+        }
+        if ![2,3,4].contains(&self.pins) {
+            errors.push(format!("fan.pins {} must be 2, 3 or 4",self.pins));
+        }
+        if self.mode == VpiFanMode::Linear && self.linear_min_temp >= self.linear_max_temp {
+            errors.push(format!("fan.linear_min_temp {} must be lower than linear_max_temp {}",self.linear_min_temp,self.linear_max_temp));
+        }
+        errors
+    }
+    /// Problems of the system the daemon can run with, the temperature is then not available
+    pub fn warnings(&self) -> Vec<String> {
+        match read_to_string(Path::new(&self.thermal_path)) {
+            Ok(_) => vec!(),
+            Err(e) => vec!(format!("fan.thermal_path {} not readable: {}",self.thermal_path,e)),
+        }
+    }
+    /// Temperature in millicelsius without logging, None if not readable
+    pub fn read_temp(&self) -> Option<i32> {
+        read_to_string(Path::new(&self.thermal_path)).ok().and_then(|s| s.trim().parse::<i32>().ok())
+    }
+    /// Target temperature in millicelsius of the Pi regulation
+    pub fn get_target(&self) -> Option<i32> {
+        if self.mode == VpiFanMode::Pi { Some(self.pi_desired_temp) } else { None }
+    }
     /// Get temperature 
     pub fn get_temp(&self) -> i32 {
         
@@ -0,0 +1,37 @@
+//! Firmware debug log source
+//! Reads the debug UART of a firmware built with traces and forward the events
+//! to the daemon log. Events are logged with target `vpi::firmware` so they are
+//! interleaved with the I2C transactions (`vpi::i2c` at trace level).
+
+use std::path::Path;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use vpi::fwdbg::{self, FwEvent};
+
+/// Time to wait before reopening the tty after a failure
+const FW_LOG_RETRY: Duration = Duration::from_secs(5);
+
+/// Spawn the thread reading the firmware UART.
+pub fn run_fw_log(tty: &Path, baud: u32) -> JoinHandle<()> {
+    let path = tty.to_path_buf();
+    thread::spawn(move || loop {
+        match fwdbg::open(&path, baud) {
+            Ok(mut reader) => {
+                info!("Firmware debug UART {} opened at {} bauds", path.display(), baud);
+                while let Some(res) = reader.next() {
+                    match res {
+                        Ok(FwEvent::Tick) => trace!(target: "vpi::firmware", "[{}s] .", reader.uptime()),
+                        Ok(ev) => info!(target: "vpi::firmware", "[{}s] {}", reader.uptime(), ev),
+                        Err(e) => {
+                            error!("Firmware debug UART {} read failed: {}", path.display(), e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Could not open firmware debug UART {} [{}]", path.display(), e),
+        }
+        thread::sleep(FW_LOG_RETRY);
+    })
+}
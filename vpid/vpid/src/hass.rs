@@ -0,0 +1,95 @@
+//! Home Assistant MQTT discovery
+//! Builds the retained discovery configs of a board, keyed by its UUID, pointing to
+//! the topics published by the MQTT module. Configs are sent on every connection
+//! and when Home Assistant comes back online (`<discovery_prefix>/status`).
+//! The switch, select & button entities send `output`, `led` & `beep` to `<prefix>/cmd`,
+//! they need a socket acl rule allowing these commands to `mqtt`.
+
+use serde_json::{json,Value};
+use crate::mqtt::VpiMqttConfig;
+
+/// LED modes accepted by `led <mode>`
+const LED_MODES: [&str; 7] = ["off","on","cycle","fast_cycle","blink","fast_blink","custom"];
+
+/// Button triggers: (id, type, subtype, template of the payload)
+const BUTTON_TRIGGERS: [(&str,&str,&str,&str); 4] = [
+    ("pwr_short","button_short_press","button_1","{{ 'press' if value_json.pwr_short > 0 else '' }}"),
+    ("pwr_long", "button_long_press", "button_1","{{ 'press' if value_json.pwr_long > 0 else '' }}"),
+    ("aux_short","button_short_press","button_2","{{ 'press' if value_json.aux_short > 0 else '' }}"),
+    ("aux_long", "button_long_press", "button_2","{{ 'press' if value_json.aux_long > 0 else '' }}"),
+];
+
+/// Discovery configs of the board as (topic, payload)
+pub fn discovery_configs(cfg: &VpiMqttConfig, uuid: &str) -> Vec<(String,Value)> {
+    let node = format!("vpi_{}",uuid.to_lowercase());
+    let device = json!({
+        "identifiers": [node],
+        "name": format!("VPi {}",&uuid[uuid.len().saturating_sub(6)..]),
+        "manufacturer": "boros",
+        "model": "Vertical Pi",
+    });
+    let topic = |component: &str, object: &str| format!("{}/{}/{}/{}/config",cfg.discovery_prefix,component,node,object);
+    // Common fields of the entities
+    let entity = |object: &str, name: &str, mut conf: Value| {
+        conf["name"] = json!(name);
+        conf["unique_id"] = json!(format!("{}_{}",node,object));
+        conf["device"] = device.clone();
+        conf["availability_topic"] = json!(cfg.topic("availability"));
+        conf
+    };
+    let mut configs = vec!(
+        (topic("sensor","rpm"), entity("rpm","Fan speed",json!({
+            "state_topic": cfg.topic("status"),
+            "value_template": "{{ value_json.rpm }}",
+            "unit_of_measurement": "RPM",
+            "state_class": "measurement",
+            "icon": "mdi:fan",
+        }))),
+        (topic("sensor","duty"), entity("duty","Fan duty",json!({
+            "state_topic": cfg.topic("fan"),
+            "value_template": "{{ (value | int * 100 / 255) | round(0) }}",
+            "unit_of_measurement": "%",
+            "state_class": "measurement",
+            "icon": "mdi:fan-speed-1",
+        }))),
+        (topic("sensor","temp"), entity("temp","SoC temperature",json!({
+            "state_topic": cfg.topic("temp"),
+            "device_class": "temperature",
+            "unit_of_measurement": "°C",
+            "state_class": "measurement",
+        }))),
+        (topic("switch","output"), entity("output","Output",json!({
+            "state_topic": cfg.topic("status"),
+            "value_template": "{{ 'ON' if value_json.out_value else 'OFF' }}",
+            "command_topic": cfg.topic("cmd"),
+            "payload_on": "output on",
+            "payload_off": "output off",
+            "state_on": "ON",
+            "state_off": "OFF",
+        }))),
+        (topic("select","led"), entity("led","LED mode",json!({
+            "command_topic": cfg.topic("cmd"),
+            "command_template": "led {{ value }}",
+            "options": LED_MODES,
+            "optimistic": true,
+            "icon": "mdi:led-on",
+        }))),
+        (topic("button","beep"), entity("beep","Beep",json!({
+            "command_topic": cfg.topic("cmd"),
+            "payload_press": "beep medium 1",
+            "icon": "mdi:volume-high",
+        }))),
+    );
+    for (id,kind,subtype,template) in BUTTON_TRIGGERS.iter() {
+        configs.push((topic("device_automation",id), json!({
+            "automation_type": "trigger",
+            "topic": cfg.topic("event/button"),
+            "value_template": template,
+            "payload": "press",
+            "type": kind,
+            "subtype": subtype,
+            "device": device.clone(),
+        })));
+    }
+    configs
+}
@@ -0,0 +1,282 @@
+//! HTTP REST API
+//! Optional HTTP listener that exposes the same commands as the socket, all
+//! answered with the JSON envelope `{"result":bool,"data":...}`:
+//!  GET  /status           -> status
+//!  GET  /stats            -> stats
+//!  POST /cmd/{verb}[/arg] -> command `verb`, args from the path and the body
+//!  GET  /keys/{k}         -> getkey k, keys may contain '/' (namespaces)
+//!  PUT  /keys/{k}         -> setkey k <body> (POST also accepted)
+//!  DELETE /keys/{k}       -> delkey k
+//!  GET  /keys?prefix=p    -> keys p
+//! Keys & prefix are percent-decoded, keys with spaces are rejected.
+//!  GET  /metrics          -> metrics in Prometheus text format
+//! Every request must authenticate with the configured `token` (`Authorization: Bearer`)
+//! or `user` & `password` (basic auth), the listener does not start without them.
+//! Commands are then authorized as principal `http` (see `acl`).
+//! Requests are served by `HTTP_WORKERS` threads. The listener is started once,
+//! changes of the `http` section need a restart.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use serde_json::json;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use crossbeam_channel::Sender;
+use tiny_http::{Server,Request,Response,Method,Header};
+use crate::cmd::{VpiCommand,VpiOrigin,exec_command};
+use crate::error::{Error,Result};
+use crate::metrics;
+use base64::Engine;
+
+/// Max size of a request body
+const HTTP_MAX_BODY: u64 = 4096;
+/// Threads serving the requests
+const HTTP_WORKERS: usize = 4;
+/// Content type of the JSON responses
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// HTTP listener configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiHttpConfig {
+    /// Address to bind, 0.0.0.0 for all interfaces
+    pub bind: String,
+    /// Port to listen
+    pub port: u16,
+    /// Bearer token of the clients
+    pub token: Option<String>,
+    /// User of the basic authentication
+    pub user: Option<String>,
+    /// Password of the basic authentication
+    pub password: Option<String>,
+}
+
+impl Default for VpiHttpConfig {
+    fn default() -> Self {
+        VpiHttpConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            token: None,
+            user: None,
+            password: None,
+        }
+    }
+}
+
+impl VpiHttpConfig {
+    /// Configuration problems
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors=vec!();
+        if self.token.as_deref().is_some_and(str::is_empty) {
+            errors.push("http token is empty".to_string());
+        }
+        if self.user.is_some() != self.password.is_some() {
+            errors.push("http user & password must be set together".to_string());
+        }
+        if self.token.is_none() && self.user.is_none() {
+            errors.push("http needs a token or user & password".to_string());
+        }
+        errors
+    }
+    /// Accepted values of the Authorization header
+    fn authorizations(&self) -> Vec<String> {
+        let mut auth=vec!();
+        if let Some(ref t) = self.token {
+            auth.push(format!("Bearer {}",t));
+        }
+        if let (Some(u),Some(p)) = (&self.user,&self.password) {
+            auth.push(format!("Basic {}",base64::engine::general_purpose::STANDARD.encode(format!("{}:{}",u,p))));
+        }
+        auth
+    }
+}
+
+/// Compare without leaking the position of the first difference
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc,(x,y)| acc | (x^y)) == 0
+}
+
+/// Check the Authorization header of the request
+fn authorized(req: &Request, auths: &[String]) -> bool {
+    req.headers().iter()
+        .filter(|h| h.field.equiv("Authorization"))
+        .any(|h| auths.iter().any(|a| same_secret(h.value.as_str().trim(), a)))
+}
+
+/// Percent-decoded key (or prefix) of the key store, None if it can't be a single argument
+fn decode_key(segments: &[&str]) -> Option<String> {
+    let key = segments.iter()
+        .map(|s| percent_encoding::percent_decode_str(s).decode_utf8().ok())
+        .collect::<Option<Vec<_>>>()?
+        .join("/");
+    if key.chars().any(|c| c.is_whitespace() || c.is_control()) { None } else { Some(key) }
+}
+
+/// Map a request to a vpid command, or the HTTP code if it can't be mapped
+fn route(method: &Method, path: &str, query: &str, body: &str) -> std::result::Result<String,u16> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').filter(|p| !p.is_empty()).collect();
+    let key = |k: &[&str]| decode_key(k).ok_or(400u16);
+    match (method, parts.as_slice()) {
+        (Method::Get, ["status"]) => Ok("status".to_string()),
+        (Method::Get, ["stats"])  => Ok("stats".to_string()),
+        (Method::Post, ["cmd", verb, args @ ..]) => {
+            let mut cmd = verb.to_string();
+            for a in args.iter().copied().chain(body.split_whitespace()) {
+                cmd.push(' ');
+                cmd.push_str(a);
+            }
+            Ok(cmd)
+        },
+        (Method::Get, ["keys"]) => {
+            let prefix = form_urlencoded::parse(query.as_bytes()).find(|(k,_)| k == "prefix").map(|(_,v)| v.into_owned()).unwrap_or_default();
+            if prefix.chars().any(|c| c.is_whitespace() || c.is_control()) { Err(400) } else { Ok(format!("keys {}",prefix)) }
+        },
+        (Method::Get, ["keys", k @ ..]) => Ok(format!("getkey {}",key(k)?)),
+        (Method::Delete, ["keys", k @ ..]) if !k.is_empty() => Ok(format!("delkey {}",key(k)?)),
+        (Method::Put, ["keys", k @ ..]) | (Method::Post, ["keys", k @ ..]) if !k.is_empty() && !body.trim().is_empty() => Ok(format!("setkey {} {}",key(k)?,body.trim())),
+        _ => Err(404),
+    }
+}
+
+/// Prometheus metrics
+fn handle_metrics(command_sender: &Sender<VpiCommand>, origin: &VpiOrigin) -> (u16,&'static str,String) {
+    match exec_command(&"metrics".to_string(), command_sender, origin) {
+        Ok(resp) => match serde_json::from_str::<serde_json::Value>(&resp) {
+            Ok(v) if v["result"].as_bool().unwrap_or(false) => (200, metrics::METRICS_CONTENT_TYPE, metrics::render(&v["data"],env!("CARGO_PKG_VERSION"))),
+            _ => (503, JSON_CONTENT_TYPE, resp),
+        },
+        Err(e) => (503, JSON_CONTENT_TYPE, json!({ "result": false, "data": e.to_string() }).to_string()),
+    }
+}
+
+/// Execute the command of a request and return the HTTP code, content type & response
+fn handle(req: &mut Request, command_sender: &Sender<VpiCommand>) -> (u16,&'static str,String) {
+    let origin = VpiOrigin::Http(req.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+    if req.method() == &Method::Get && req.url().split('?').next() == Some("/metrics") {
+        return handle_metrics(command_sender, &origin);
+    }
+    let (code,resp) = handle_json(req, command_sender, &origin);
+    (code, JSON_CONTENT_TYPE, resp)
+}
+
+/// Execute the command of a request and return the HTTP code & JSON response
+fn handle_json(req: &mut Request, command_sender: &Sender<VpiCommand>, origin: &VpiOrigin) -> (u16,String) {
+    let mut body = String::new();
+    if req.as_reader().take(HTTP_MAX_BODY).read_to_string(&mut body).is_err() {
+        return (400, json!({ "result": false, "data": "Invalid body" }).to_string());
+    }
+    let path = req.url().split('?').next().unwrap_or("").to_string();
+    let query = req.url().split('?').nth(1).unwrap_or("").to_string();
+    let cmd = match route(req.method(), &path, &query, &body) {
+        Ok(cmd) => cmd,
+        Err(400) => return (400, json!({ "result": false, "data": format!("Invalid key {}",path) }).to_string()),
+        Err(code) => return (code, json!({ "result": false, "data": format!("Not found {} {}",req.method(),path) }).to_string()),
+    };
+    match exec_command(&cmd, command_sender, origin) {
+        Ok(resp) => {
+            let ok = serde_json::from_str::<serde_json::Value>(&resp).ok().and_then(|v| v["result"].as_bool()).unwrap_or(false);
+            let missing = cmd.starts_with("getkey ") || cmd.starts_with("delkey ");
+            let code = if ok { 200 } else if missing { 404 } else { 400 };
+            (code, resp)
+        },
+        Err(e) => {
+            let code = match e {
+                Error::CommandParse { .. } => 400,
+                Error::CommandRecv { .. } => 504,
+                _ => 503,
+            };
+            (code, json!({ "result": false, "data": e.to_string() }).to_string())
+        },
+    }
+}
+
+/// Authenticate, execute & answer a request
+fn serve(mut req: Request, command_sender: &Sender<VpiCommand>, auths: &[String]) {
+    if !authorized(&req, auths) {
+        warn!("HTTP {} {} from {:?} not authorized",req.method(),req.url(),req.remote_addr());
+        let content = Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"vpid\""[..]).unwrap();
+        let _=req.respond(Response::from_string(json!({ "result": false, "data": "Unauthorized" }).to_string()).with_status_code(401).with_header(content));
+        return;
+    }
+    let (code,content_type,resp) = handle(&mut req, command_sender);
+    debug!("HTTP {} {} -> {}",req.method(),req.url(),code);
+    let content = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+    let _=req.respond(Response::from_string(resp).with_status_code(code).with_header(content));
+}
+
+/// Start the `HTTP_WORKERS` threads serving the requests, returns the first one
+pub fn run_http(cfg: &VpiHttpConfig, command_sender_orig: &Sender<VpiCommand>) -> Result<JoinHandle<()>> {
+    let addr = format!("{}:{}",cfg.bind,cfg.port);
+    if let Some(msg) = cfg.validate().first() {
+        return Err(Error::HttpBind { addr, msg: msg.clone() });
+    }
+    let server = Arc::new(Server::http(&addr).map_err(|e| Error::HttpBind { addr: addr.clone(), msg: e.to_string() })?);
+    let auths = Arc::new(cfg.authorizations());
+    let mut handles = vec!();
+    for i in 0..HTTP_WORKERS {
+        let server = server.clone();
+        let auths = auths.clone();
+        let command_sender = command_sender_orig.clone();
+        let handle = thread::Builder::new().name(format!("vpid-http-{}",i)).spawn(move || {
+            for req in server.incoming_requests() {
+                serve(req, &command_sender, &auths);
+            }
+        }).map_err(|e| Error::HttpBind { addr: addr.clone(), msg: e.to_string() })?;
+        handles.push(handle);
+    }
+    Ok(handles.swap_remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        assert_eq!(route(&Method::Get, "/status", "", ""), Ok("status".to_string()));
+        assert_eq!(route(&Method::Get, "/stats/", "", ""), Ok("stats".to_string()));
+        assert_eq!(route(&Method::Post, "/cmd/fan", "", "100\n"), Ok("fan 100".to_string()));
+        assert_eq!(route(&Method::Post, "/cmd/led/1", "", ""), Ok("led 1".to_string()));
+        assert_eq!(route(&Method::Get, "/keys", "prefix=net/", ""), Ok("keys net/".to_string()));
+        assert_eq!(route(&Method::Get, "/keys", "", ""), Ok("keys ".to_string()));
+        assert_eq!(route(&Method::Get, "/keys/net/ip", "", ""), Ok("getkey net/ip".to_string()));
+        assert_eq!(route(&Method::Put, "/keys/mode", "", " eco "), Ok("setkey mode eco".to_string()));
+        assert_eq!(route(&Method::Post, "/keys/mode", "", "eco"), Ok("setkey mode eco".to_string()));
+        assert_eq!(route(&Method::Delete, "/keys/mode", "", ""), Ok("delkey mode".to_string()));
+        assert_eq!(route(&Method::Put, "/keys/mode", "", ""), Err(404));
+        assert_eq!(route(&Method::Delete, "/keys", "", ""), Err(404));
+        assert_eq!(route(&Method::Get, "/cmd/fan", "", ""), Err(404));
+        assert_eq!(route(&Method::Post, "/status", "", ""), Err(404));
+        assert_eq!(route(&Method::Get, "/", "", ""), Err(404));
+    }
+
+    #[test]
+    fn decoded_keys() {
+        assert_eq!(route(&Method::Get, "/keys", "limit=5&prefix=net%2Fwlan0&x=1", ""), Ok("keys net/wlan0".to_string()));
+        assert_eq!(route(&Method::Get, "/keys", "prefix=a+b", ""), Err(400));
+        assert_eq!(route(&Method::Get, "/keys/caf%C3%A9/ip", "", ""), Ok("getkey café/ip".to_string()));
+        assert_eq!(route(&Method::Get, "/keys/a%20b", "", ""), Err(400));
+        assert_eq!(route(&Method::Put, "/keys/a%20b", "", "v"), Err(400));
+        assert_eq!(route(&Method::Delete, "/keys/a%0Ab", "", ""), Err(400));
+        assert_eq!(route(&Method::Get, "/keys/%FF", "", ""), Err(400));
+    }
+
+    #[test]
+    fn credentials() {
+        let mut cfg = VpiHttpConfig::default();
+        assert_eq!(cfg.validate().len(), 1);
+        cfg.user = Some("vpi".to_string());
+        assert_eq!(cfg.validate(), vec!("http user & password must be set together".to_string()));
+        cfg.password = Some("secret".to_string());
+        assert!(cfg.validate().is_empty());
+        assert_eq!(cfg.authorizations(), vec!("Basic dnBpOnNlY3JldA==".to_string()));
+        cfg.token = Some("t0k".to_string());
+        assert_eq!(cfg.authorizations()[0], "Bearer t0k");
+        assert!(same_secret("Bearer t0k", "Bearer t0k"));
+        assert!(!same_secret("Bearer t0K", "Bearer t0k"));
+        assert!(!same_secret("Bearer t0", "Bearer t0k"));
+    }
+}
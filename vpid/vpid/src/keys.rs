@@ -0,0 +1,303 @@
+//! Key-value store
+//! Keys set by `setkey`/`setkeyex` are kept across reloads and persisted to `file`,
+//! rewritten atomically (synced temporary file & rename) once changes settle for
+//! `KEY_SAVE_DELAY` and on exit. Keys may expire after a TTL in seconds. Namespaces are
+//! key prefixes separated by `/` listed with `keys <prefix>`; mini services can only
+//! set & delete keys of their namespace `service/<name>/`.
+
+use serde::{Deserialize,Serialize};
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use std::fs::{self,File};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+use crate::cmd::{VpiCommandBody,VpiOrigin};
+
+/// Time a change waits to be saved, changes within are saved together
+const KEY_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Key store configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiKeysConfig {
+    /// File to persist the keys, kept only in memory if not set
+    pub file: Option<String>,
+    /// Max number of keys
+    pub max_keys: usize,
+    /// Max length of a key
+    pub max_key: usize,
+    /// Max length of a value
+    pub max_value: usize,
+}
+
+impl Default for VpiKeysConfig {
+    fn default() -> Self {
+        VpiKeysConfig {
+            file: Some("/var/lib/vpid/keys.json".to_string()),
+            max_keys: 1024,
+            max_key: 128,
+            max_value: 4096,
+        }
+    }
+}
+
+/// Stored value
+#[derive(Serialize,Deserialize,Debug,Clone)]
+struct KeyEntry {
+    value: String,
+    /// Expiration time in seconds since epoch
+    #[serde(default,skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+impl KeyEntry {
+    /// Not expired at `t`
+    fn live(&self, t: u64) -> bool {
+        self.expires.map(|x| x > t).unwrap_or(true)
+    }
+}
+
+/// Seconds since epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Namespace of the keys of a mini service
+pub fn namespace(service: &str) -> String {
+    format!("service/{}/",service)
+}
+
+/// Check that a mini service only changes keys of its namespace
+pub fn check_namespace(origin: &VpiOrigin, body: &VpiCommandBody) -> Result<(),String> {
+    let key = match body {
+        VpiCommandBody::SetKey(key,_,_) | VpiCommandBody::DelKey(key) => key,
+        _ => return Ok(()),
+    };
+    match origin {
+        VpiOrigin::Service(name) if !key.starts_with(&namespace(name)) => Err(format!("Key '{}' out of the namespace {} of service {}",key,namespace(name),name)),
+        _ => Ok(()),
+    }
+}
+
+/// Write `js` to a synced temporary file renamed to `path`, syncing the directory
+fn write_file(path: &str, js: &str) -> std::io::Result<()> {
+    let dir = match Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let tmp = format!("{}.tmp",path);
+    let mut f = File::create(&tmp)?;
+    f.write_all(js.as_bytes())?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    File::open(dir)?.sync_all()
+}
+
+/// Key-value store
+pub struct KeyStore {
+    cfg: VpiKeysConfig,
+    entries: BTreeMap<String,KeyEntry>,
+    /// Time of the first change not saved
+    dirty: Option<Instant>,
+}
+
+impl KeyStore {
+    /// Create the store loading the persisted keys
+    pub fn open(cfg: &VpiKeysConfig) -> Self {
+        let mut store = KeyStore { cfg: cfg.clone(), entries: BTreeMap::new(), dirty: None };
+        store.load();
+        store
+    }
+    /// Change the configuration (reload). Keys are kept and saved to the new file.
+    pub fn configure(&mut self, cfg: &VpiKeysConfig) {
+        if *cfg != self.cfg {
+            self.cfg = cfg.clone();
+            self.save();
+        }
+    }
+    /// Load the keys of the file
+    fn load(&mut self) {
+        let path = match self.cfg.file {
+            Some(ref f) => f.clone(),
+            None => return,
+        };
+        match fs::read_to_string(&path) {
+            Ok(js) => match serde_json::from_str::<BTreeMap<String,KeyEntry>>(&js) {
+                Ok(entries) => {
+                    self.entries = entries; // Expired keys are notified by the next expire
+                    info!("Key store loaded {} keys from {}",self.entries.len(),path);
+                },
+                Err(e) => warn!("Key store file {} invalid, keys discarded: {}",path,e),
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => info!("Key store file {} not found, empty store",path),
+            Err(e) => warn!("Key store file {} could not be read: {}",path,e),
+        }
+    }
+    /// Write the keys to the store file now
+    pub fn save(&mut self) {
+        self.dirty = None;
+        let path = match self.cfg.file {
+            Some(ref f) => f.clone(),
+            None => return,
+        };
+        let res = serde_json::to_string(&self.entries).map_err(|e| e.to_string())
+            .and_then(|js| write_file(&path, &js).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            warn!("Key store could not be saved to {}: {}",path,e);
+        }
+    }
+    /// Save the changes older than `KEY_SAVE_DELAY`, called periodically
+    pub fn flush(&mut self) {
+        if self.dirty.map(|t| t.elapsed() >= KEY_SAVE_DELAY).unwrap_or(false) {
+            self.save();
+        }
+    }
+    /// Mark the store as changed, saved by the next `flush` after `KEY_SAVE_DELAY`
+    fn changed(&mut self) {
+        self.dirty.get_or_insert_with(Instant::now);
+    }
+    /// Remove the expired keys, returns their names to notify them as deleted
+    pub fn expire(&mut self) -> Vec<String> {
+        let t = now();
+        let expired: Vec<String> = self.entries.iter().filter(|(_,e)| !e.live(t)).map(|(k,_)| k.clone()).collect();
+        for k in expired.iter() {
+            self.entries.remove(k);
+        }
+        if !expired.is_empty() {
+            self.changed();
+        }
+        expired
+    }
+    /// Value of a key
+    pub fn get(&self, key: &str) -> Option<String> {
+        let t = now();
+        self.entries.get(key).filter(|e| e.live(t)).map(|e| e.value.clone())
+    }
+    /// Values of all keys
+    pub fn values(&self) -> BTreeMap<String,String> {
+        let t = now();
+        self.entries.iter().filter(|(_,e)| e.live(t)).map(|(k,e)| (k.clone(),e.value.clone())).collect()
+    }
+    /// Set a key, expiring after `ttl` seconds if set. True if the value changed
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool,String> {
+        if key.is_empty() || key.len() > self.cfg.max_key {
+            return Err(format!("Key length must be 1-{}",self.cfg.max_key));
+        }
+        if value.len() > self.cfg.max_value {
+            return Err(format!("Value longer than {}",self.cfg.max_value));
+        }
+        let t = now();
+        if !self.entries.contains_key(key) && self.entries.values().filter(|e| e.live(t)).count() >= self.cfg.max_keys {
+            return Err(format!("Key store full, {} keys",self.cfg.max_keys));
+        }
+        let expires = ttl.map(|ttl| t+ttl as u64);
+        let old = self.entries.insert(key.to_string(), KeyEntry { value: value.to_string(), expires });
+        self.changed();
+        Ok(old.filter(|e| e.live(t)).map(|e| e.value != value).unwrap_or(true))
+    }
+    /// Delete a key, false if not found
+    pub fn del(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(e) => {
+                self.changed();
+                e.live(now())
+            },
+            None => false,
+        }
+    }
+    /// Keys starting with `prefix`, sorted
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let t = now();
+        self.entries.range(prefix.to_string()..).take_while(|(k,_)| k.starts_with(prefix)).filter(|(_,e)| e.live(t)).map(|(k,_)| k.clone()).collect()
+    }
+}
+
+impl Drop for KeyStore {
+    fn drop(&mut self) {
+        if self.dirty.is_some() {
+            self.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cfg(name: &str) -> VpiKeysConfig {
+        let path = std::env::temp_dir().join(format!("vpid-keys-{}-{}/keys.json",name,std::process::id()));
+        let _ = fs::remove_file(&path);
+        VpiKeysConfig { file: Some(path.display().to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn saves_batched_and_on_drop() {
+        let cfg = temp_cfg("save");
+        let path = cfg.file.clone().unwrap();
+        let mut store = KeyStore::open(&cfg);
+        assert_eq!(store.set("a","1",None), Ok(true));
+        assert_eq!(store.set("a","1",None), Ok(false));
+        store.set("b","2",None).unwrap();
+        store.flush(); // Too soon
+        assert!(fs::metadata(&path).is_err());
+        store.dirty = Some(Instant::now()-KEY_SAVE_DELAY);
+        store.flush();
+        assert!(fs::read_to_string(&path).unwrap().contains(r#""b":{"value":"2"}"#));
+        assert!(store.del("b"));
+        drop(store);
+        let store = KeyStore::open(&cfg);
+        assert_eq!(store.keys(""), vec!("a".to_string()));
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let mut store = KeyStore::open(&VpiKeysConfig { file: None, max_keys: 2, max_key: 4, max_value: 3 });
+        assert!(store.set("","v",None).is_err());
+        assert!(store.set("12345","v",None).is_err());
+        assert!(store.set("k","1234",None).is_err());
+        store.set("k1","v",None).unwrap();
+        store.set("k2","v",None).unwrap();
+        assert!(store.set("k3","v",None).is_err());
+        assert!(store.set("k2","w",None).is_ok());
+        assert!(store.dirty.is_some());
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let mut store = KeyStore::open(&VpiKeysConfig { file: None, max_keys: 2, ..Default::default() });
+        store.set("net/ip","10.0.0.2",Some(60)).unwrap();
+        store.set("net/up","1",None).unwrap();
+        assert!(store.expire().is_empty());
+        store.entries.get_mut("net/ip").unwrap().expires = Some(now()); // Expired now
+        assert_eq!(store.get("net/ip"), None);
+        assert_eq!(store.keys("net/"), vec!("net/up".to_string()));
+        assert_eq!(store.values().len(), 1);
+        assert!(store.set("other","v",None).is_ok()); // Expired keys don't count
+        assert_eq!(store.set("net/ip","10.0.0.3",None), Ok(true));
+        store.entries.get_mut("net/ip").unwrap().expires = Some(now()-1);
+        assert!(!store.del("net/ip")); // Already expired
+        assert!(store.del("other"));
+        store.set("net/ip","10.0.0.4",Some(0)).unwrap();
+        store.dirty = None;
+        assert_eq!(store.expire(), vec!("net/ip".to_string()));
+        assert!(store.dirty.is_some());
+        assert!(store.expire().is_empty());
+    }
+
+    #[test]
+    fn service_namespace() {
+        let svc = VpiOrigin::Service("net".to_string());
+        let set = |k: &str| VpiCommandBody::SetKey(k.to_string(),"v".to_string(),None);
+        assert!(check_namespace(&svc, &set("service/net/ip")).is_ok());
+        assert!(check_namespace(&svc, &set("service/network/ip")).is_err());
+        assert!(check_namespace(&svc, &set("mode")).is_err());
+        assert!(check_namespace(&svc, &VpiCommandBody::DelKey("mode".to_string())).is_err());
+        assert!(check_namespace(&svc, &VpiCommandBody::GetKey("mode".to_string())).is_ok());
+        assert!(check_namespace(&VpiOrigin::Lua("rule".to_string()), &set("mode")).is_ok());
+        assert!(check_namespace(&VpiOrigin::Daemon, &set("mode")).is_ok());
+    }
+}
@@ -9,24 +9,28 @@ extern crate crossbeam_channel;
 extern crate clap;
 
 use simple_logger::SimpleLogger;
-use std::path::PathBuf;
+use std::path::{Path,PathBuf};
 use std::str::FromStr;
-use clap::App;
+use clap::{App,Arg,SubCommand};
 use std::thread;
 use std::time::{Duration,Instant};
 use crossbeam_channel::{bounded,tick,never,Sender,Receiver};
 use signal_hook::{iterator::Signals, SIGTERM, SIGHUP, SIGINT};
 use std::process::exit;
-use std::collections::HashMap;
+use std::sync::Arc;
 use serde_json::json;
 
 // Internal
 use vpi::{Vpi};//,VpiStatus,VpiTimes};
 use vpi::cmd::{VpiCmd};
-use cmd::{VpiCommand,VpiCommandBody};
-use config::VpiConfig;
+use cmd::{VpiCommand,VpiCommandBody,VpiOrigin};
+use audit::Audit;
+use keys::KeyStore;
+use config::{VpiConfig,VpiConfigSource};
+use fan::VpiFanConfig;
 use engine::{Engine};
-use crate::error::{Result,ResultExt,I2cOpen,VpiConfigureError};
+use events::{EventBus,VpiEvent};
+use crate::error::{Error,Result,ResultExt,I2cOpen,VpiConfigureError};
 
 // Modules declaration
 mod error;
@@ -36,6 +40,18 @@ mod sock;
 mod engine;
 mod cmd;
 mod display;
+mod fwlog;
+mod events;
+mod http;
+mod mqtt;
+mod hass;
+mod metrics;
+mod acl;
+mod audit;
+mod keys;
+mod watch;
+mod notify;
+mod sysinfo;
 
 // Constant
 const VPID_VERSION :&'static str = "0.1.1";
@@ -50,7 +66,15 @@ fn main() -> ! {
                             "-s, --socket=[socket] 'Socket of vpid service, default:/var/run/vpid.sock'
                              -c, --config=[file]   'Config file, default:/etc/vpid/vpid.yml'
                              -d, --device=[i2cdev] 'i2c-dev path, default:/dev/i2c-1'
-                             -a, --address=[addr]  'i2c address, default:0x33'")
+                             -a, --address=[addr]  'i2c address, default:0x33'
+                             -u, --uart=[tty]      'Log firmware debug traces from UART, e.g. /dev/ttyS0'
+                             -b, --baud=[baud]     'Baud rate of the debug UART, default:9600'
+                             --print-schema        'Print the JSON Schema of the config file and exit'")
+                          .arg(Arg::with_name("set").long("set").value_name("key=value").takes_value(true)
+                               .multiple(true).number_of_values(1)
+                               .help("Override a config value, e.g. fan.mode=Pi. Repeatable"))
+                          .subcommand(SubCommand::with_name("check-config")
+                                      .about("Validate the config file: syntax, ranges, Lua scripts & commands"))
                           .get_matches();
 
     let socket_path=     matches.value_of("socket").unwrap_or("/var/run/vpid.sock");
@@ -59,6 +83,18 @@ fn main() -> ! {
     let device  =   PathBuf::from_str(device_s).unwrap();
     let address_s=  matches.value_of("address").unwrap_or("0x33");
     let address  =  vpi::from_str_address(address_s).unwrap_or(0x33u8);
+    let cfg_source = VpiConfigSource {
+        path: PathBuf::from(cfg_file),
+        sets: matches.values_of("set").map(|v| v.map(|s| s.to_string()).collect()).unwrap_or_default(),
+    };
+
+    if matches.is_present("print-schema") {
+        println!("{}",serde_json::to_string_pretty(&VpiConfig::schema()).unwrap_or_default());
+        exit(0);
+    }
+    if matches.subcommand_name() == Some("check-config") {
+        exit(check_config(&cfg_source));
+    }
 
     // Init log
     //simple_logger::init_by_env();
@@ -79,20 +115,71 @@ fn main() -> ! {
         exit(1);
     } 
     info!("Validating configuration file...");
-    if let Err(e) = VpiConfig::load(&cfg_path) {
-        error!("Configuration file invalid [{}] Aborting",e);
-        exit(1);
+    // Same checks as check-config & reload, unknown keys are only warned
+    let init_cfg = match cfg_source.load_valid() {
+        Ok(cfg) => cfg,
+        Err(Error::InvalidConfig { problems, .. }) => {
+            for problem in problems {
+                error!("Configuration: {}",problem);
+            }
+            error!("Configuration file {} invalid. Aborting",cfg_file);
+            exit(1);
+        },
+        Err(e) => {
+            error!("Configuration file invalid [{}] Aborting",watch::describe_error(&e));
+            exit(1);
+        }
+    };
+    for warning in init_cfg.warnings() {
+        warn!("Configuration: {}",warning);
     }
     info!("Configuration validated!");
+    // Firmware debug log source
+    if let Some(tty) = matches.value_of("uart") {
+        let baud = matches.value_of("baud").and_then(|b| b.parse::<u32>().ok()).unwrap_or(vpi::fwdbg::FW_DBG_BAUD);
+        info!("Starting firmware debug log from {} at {} bauds",tty,baud);
+        fwlog::run_fw_log(Path::new(tty),baud);
+    }
     // socket server
     let (command_sender,command_receiver) = bounded::<VpiCommand>(15);
+    let events = EventBus::new();
     info!("Starting socket server");
-    if let Err(e) = sock::run_socket(&PathBuf::from(socket_path),&command_sender) {
+    if let Err(e) = sock::run_socket(&PathBuf::from(socket_path),&command_sender,&events,&init_cfg.socket) {
         error!("Could not start socket server [{}] Aborting.",e);
         exit(2);
     }
     info!("Socket server started!");
+    // HTTP server
+    if let Some(ref http_cfg) = init_cfg.http {
+        if !init_cfg.socket.grants_commands("http") {
+            warn!("No socket acl rule allows commands to principal http, HTTP clients can only query");
+        }
+        info!("Starting HTTP server on {}:{}",http_cfg.bind,http_cfg.port);
+        if let Err(e) = http::run_http(http_cfg,&command_sender) {
+            error!("Could not start HTTP server [{}] Aborting.",e);
+            sock::close_socket(&PathBuf::from(socket_path));
+            exit(2);
+        }
+        info!("HTTP server started!");
+    }
+    // MQTT
+    if let Some(ref mqtt_cfg) = init_cfg.mqtt {
+        if !init_cfg.socket.grants_commands("mqtt") {
+            warn!("No socket acl rule allows commands to principal mqtt, MQTT commands can only query");
+        }
+        info!("Starting MQTT client to {}:{} prefix:{}",mqtt_cfg.host,mqtt_cfg.port,mqtt_cfg.prefix);
+        mqtt::run_mqtt(mqtt_cfg,&command_sender,&events);
+    }
+    // Event notifications
+    notify::run_notify(&init_cfg.notify,&command_sender,&events);
     
+    // Config watcher
+    if let Some(ref watch_cfg) = init_cfg.watch {
+        info!("Watching configuration file {} for changes",cfg_file);
+        if let Err(e) = watch::run_watch(watch_cfg,&cfg_source,&command_sender) {
+            error!("Config watcher not started [{}]",e);
+        }
+    }
     // Signal manager
     info!("Init signal manager");
     let signals = Signals::new(&[SIGTERM,SIGHUP,SIGINT]).unwrap();
@@ -101,9 +188,9 @@ fn main() -> ! {
         for sig in signals.forever() {
             info!("OS signal received [code:{}]",sig);
             if sig==SIGHUP {
-                let _=signal_sender.send(VpiCommand::new_nbc(VpiCommandBody::ReloadConfig));
+                let _=signal_sender.send(VpiCommand::new_nbc(VpiCommandBody::ReloadConfig).with_origin(VpiOrigin::Signal,"SIGHUP"));
             } else {
-                let _=signal_sender.send(VpiCommand::new_nbc(VpiCommandBody::Signal(sig)));
+                let _=signal_sender.send(VpiCommand::new_nbc(VpiCommandBody::Signal(sig)).with_origin(VpiOrigin::Signal,&format!("signal {}",sig)));
             }
         }
     });
@@ -111,45 +198,115 @@ fn main() -> ! {
     // Launch server daemon
     info!("Staring the service");
     let mut return_code:i32=0;
-    loop {
-        let res = serve(&cfg_path,&device,address,&command_sender,&command_receiver);
-        if let Ok(ret) =  res {
-            if ret == RET_CODE_EXIT { 
-                break;
-            } // exit loop to exit
-        } else {
-            error!("Fatal error: Aborting vpid exectuion: {:?}",res);
-            return_code=3;
-            break;
-        }
+    if let Err(e) = serve(&cfg_source,init_cfg,&device,address,&command_sender,&command_receiver,&events) {
+        error!("Fatal error: Aborting vpid exectuion: {:?}",e);
+        return_code=3;
     }
     info!("Shuting down service gracefully...");
     sock::close_socket(&PathBuf::from(socket_path));
     exit(return_code);
 }
 
-const RET_CODE_RELOAD:i32 =0i32;
+/// Validate the config file, exit code 0 if valid
+fn check_config(cfg_source: &VpiConfigSource) -> i32 {
+    let cfg_file = cfg_source.path.display();
+    match cfg_source.load_checked() {
+        Ok((cfg,unknown)) => {
+            let mut problems: Vec<String>=unknown.iter().map(|k| format!("unknown key {}",k)).collect();
+            problems.extend(cfg.validate());
+            for p in problems.iter() {
+                println!("{}: {}",cfg_file,p);
+            }
+            for w in cfg.warnings() {
+                println!("{}: warning: {}",cfg_file,w);
+            }
+            if problems.is_empty() {
+                println!("{}: configuration OK",cfg_file);
+                0
+            } else {
+                1
+            }
+        },
+        Err(e) => {
+            println!("{}",watch::describe_error(&e));
+            1
+        }
+    }
+}
+
 const RET_CODE_EXIT:i32   =1i32;
 
-fn serve(cfg_file : &PathBuf,
+/// Watchdog autofeed timer after setting the watchdog to `wdg`, half the configured time
+fn autofeed_timer(wdg: u8, cfg: &VpiConfig) -> Receiver<Instant> {
+    if wdg == 0u8 || !cfg.watchdog_autofeed {
+        info!("Disabling watchdog autofeed");
+        never::<Instant>()
+    } else {
+        info!("Enable watchdog autofeed to {} ms",(cfg.watchdog as u64*1000u64)/2u64);
+        tick(Duration::from_millis( (cfg.watchdog as u64*1000u64)/2u64 ) )
+    }
+}
+
+/// Push to the board only the registers changed by a new configuration
+fn reconfigure_board(vpi: &mut Vpi, old: &VpiConfig, new: &VpiConfig) -> Result<()> {
+    let fan_regs = |c: &VpiConfig| c.fan.as_ref().map(|f| (f.get_pwmfreq(),f.get_divisor()));
+    let times = |c: &VpiConfig| (c.short_time,c.space_time,c.hold_time,c.grace_time);
+    if fan_regs(old) != fan_regs(new) || times(old) != times(new) {
+        if let Some(ref fan) = new.fan {
+            info!("Configure pwm frequency {} Hz and fan divisor to {} turns",fan.get_pwmfreq(),fan.get_divisor());
+            vpi.pwm_freq(fan.get_pwmfreq()).rev_divisor(fan.get_divisor());
+        }
+        info!("Set button timmings short:{} ms space:{} ms hold:{} s grace:{} s",new.short_time,new.space_time,new.hold_time,new.grace_time);
+        vpi.timings(&new.times());
+        vpi.config().context(VpiConfigureError {} )?;
+    }
+    let mut regs=vec!();
+    if old.watchdog != new.watchdog {
+        regs.push(VpiCmd::Wdg(new.watchdog));
+    }
+    if old.wake != new.wake {
+        regs.push(VpiCmd::Wake(new.wake));
+    }
+    if old.wake_irq != new.wake_irq {
+        regs.push(VpiCmd::IrqWake(new.wake_irq));
+    }
+    let mut res=Ok(());
+    for reg in regs.iter() { // All tried, the first failure returned
+        match vpi.run(reg,false) {
+            Ok(output) => info!("Command executed:{}",output),
+            Err(e) => {
+                error!("Command {:?} failed [{}]",reg,e);
+                if res.is_ok() {
+                    res=Err(e).context(VpiConfigureError {});
+                }
+            }
+        }
+    }
+    res
+}
+
+fn serve(cfg_source : &VpiConfigSource,
+         init_cfg: VpiConfig,
          device : &PathBuf,
          addr: u8,
          command_sender: &Sender<VpiCommand>,
-         command_receiver : &Receiver<VpiCommand>) -> Result<i32>{
+         command_receiver : &Receiver<VpiCommand>,
+         events: &EventBus) -> Result<i32>{
 
-    // Reload the confing
-    let cfg=config::VpiConfig::load(cfg_file)?;
+    // Configuration validated at startup, the source is only reloaded on ReloadConfig
+    let mut cfg=Arc::new(init_cfg);
+    let mut audit=Audit::new(&cfg.audit);
+    let acl=cfg.socket.clone(); // Access rules of the socket, HTTP & MQTT commands
+    let mut key_storage=KeyStore::open(&cfg.keys);
     // Init i2c
     let mut vpi=Vpi::new(Some(addr as u16),false);
     vpi.open(device).context( I2cOpen { dev: device, addr: vpi.get_addr() } )?;
     let mut last_status = vpi.check_status(0).context(I2cOpen { dev: device, addr: vpi.get_addr() } )?;
-    
-    // Set up key storage
-    let mut key_storage: HashMap<String,String> = HashMap::new();
-    info!("Key storage initialized");
+    let mut last_stats = vpi.get_stats();
+    let mut last_rpm = last_status.rpm;
 
     // Set up timers
-    let monitor= tick(cfg.get_poll_time()); // tick(Duration::from_secs(1));
+    let mut monitor= tick(cfg.get_poll_time()); // tick(Duration::from_secs(1));
     let mut auto_feed : Receiver<Instant>=never::<Instant>(); // initialy off
     let mut fan_control: Receiver<Instant>=never::<Instant>(); // intially off
 
@@ -158,7 +315,7 @@ fn serve(cfg_file : &PathBuf,
     if let Some(ref fan) = cfg.fan {
         info!("Configure pwm frequency {} Hz and fan divisor to {} turns",fan.get_pwmfreq(),fan.get_divisor());
         vpi.pwm_freq(fan.get_pwmfreq()).rev_divisor(fan.get_divisor());
-        fan_control = tick(Duration::from_secs(3));  
+        fan_control = tick(fan.get_sample());
     }
     // Times
     vpi.timings(&cfg.times());
@@ -172,7 +329,7 @@ fn serve(cfg_file : &PathBuf,
     let _=command_sender.send(VpiCommand::new_nbc(VpiCommandBody::Basic(VpiCmd::Wake(cfg.wake))   ));
     let _=command_sender.send(VpiCommand::new_nbc(VpiCommandBody::Basic(VpiCmd::IrqWake(cfg.wake_irq)) ));
     // Rule & exectution engine
-    let mut engine=Engine::new(&cfg,&command_sender);
+    let mut engine=Engine::new(cfg.clone(),command_sender,events);
     engine.start_miniservices()?;
     
     info!("Rule engine started");
@@ -183,11 +340,21 @@ fn serve(cfg_file : &PathBuf,
             recv(monitor) -> _ => {
                 engine.test_childs(false);
                 engine.test_lua_childs(false);
+                for key in key_storage.expire() {
+                    info!("Key '{}' expired",key);
+                    events.publish(VpiEvent::Key { key: key.clone(), value: None });
+                    let _=engine.run_key_rules(&key,None,&last_status,&last_stats,&key_storage,vpi.get_fan_value());
+                }
+                key_storage.flush();
                 if let Ok(st) = vpi.monitor() {
-                    if st.has_changed(&last_status) {
-                        let _=engine.run_rules(&st,&vpi.get_stats());
+                    let stats=vpi.get_stats();
+                    for ev in VpiEvent::from_status(&st,&stats,last_rpm,&last_stats) {
+                        events.publish(ev);
                     }
+                    if st.has_rpm { last_rpm=st.rpm; }
+                    let _=engine.run_rules(&st,&stats,&key_storage,vpi.get_fan_value());
                     last_status=st;
+                    last_stats=stats;
                 } else {
                     warn!("Failed to monitor");
                 }
@@ -200,6 +367,7 @@ fn serve(cfg_file : &PathBuf,
                 if fvalue != vpi.get_fan_value() {
                     let _=vpi.fan_now(fvalue);
                     trace!("Adjusted fan value to {}",fvalue);
+                    events.publish(VpiEvent::Fan { duty: fvalue });
                 }
             },
             recv(command_receiver) -> cmdr => {
@@ -207,41 +375,125 @@ fn serve(cfg_file : &PathBuf,
                     error!("Error receiving command {:?}",cmdr);
                 }
                 let cmd=cmdr.unwrap_or(VpiCommand::new_nbc(VpiCommandBody::Basic(VpiCmd::Nop)));
+                let verb=cmd.text.split_whitespace().next().unwrap_or("").to_lowercase();
+                let denied=if acl.allowed(&cmd.origin,&cmd.text,cmd.body.is_query()) {
+                    keys::check_namespace(&cmd.origin,&cmd.body).err()
+                } else {
+                    Some(format!("Permission denied: '{}' not allowed for {}",verb,cmd.origin))
+                };
+                if let Some(reason) = denied {
+                    warn!("Command '{}' denied to {}: {}",verb,cmd.origin,reason);
+                    cmd.send_response(json!({ "result": false, "data": reason }).to_string());
+                    audit.denied(&cmd);
+                    continue;
+                }
                 match cmd.body {
                     VpiCommandBody::ReloadConfig => {
-                        let _=cmd.send_ok();
-                        return Ok(RET_CODE_RELOAD);
+                        match cfg_source.load_valid() {
+                            Ok(new_cfg) => {
+                                info!("Reloading configuration");
+                                for warning in new_cfg.warnings() {
+                                    warn!("Configuration: {}",warning);
+                                }
+                                let new_cfg=Arc::new(new_cfg);
+                                if let Err(e) = reconfigure_board(&mut vpi,&cfg,&new_cfg) {
+                                    error!("Board reconfiguration failed [{}]",e);
+                                }
+                                if new_cfg.watchdog != cfg.watchdog || new_cfg.watchdog_autofeed != cfg.watchdog_autofeed {
+                                    auto_feed=autofeed_timer(new_cfg.watchdog,&new_cfg);
+                                }
+                                if new_cfg.get_poll_time() != cfg.get_poll_time() {
+                                    monitor=tick(new_cfg.get_poll_time());
+                                }
+                                if new_cfg.fan != cfg.fan { // Keep the integrator of the PI regulation
+                                    let mut fan=new_cfg.fan.clone();
+                                    if let (Some(new_fan),Some(old_fan)) = (fan.as_mut(),fan_controller.as_ref()) {
+                                        new_fan.keep_state(old_fan);
+                                    }
+                                    let sample=fan.as_ref().map(|f| f.get_sample());
+                                    if sample != fan_controller.as_ref().map(|f| f.get_sample()) {
+                                        fan_control = match sample { Some(s) => tick(s), None => never::<Instant>() };
+                                    }
+                                    fan_controller=fan;
+                                }
+                                if new_cfg.socket != cfg.socket || new_cfg.http != cfg.http || new_cfg.mqtt != cfg.mqtt || new_cfg.watch != cfg.watch || new_cfg.notify != cfg.notify {
+                                    warn!("Changes of socket, http, mqtt, watch & notify sections need a restart");
+                                }
+                                audit.configure(&new_cfg.audit);
+                                key_storage.configure(&new_cfg.keys);
+                                engine.reconfigure(new_cfg.clone());
+                                cfg=new_cfg;
+                                cmd.send_ok();
+                            },
+                            Err(e) => {
+                                error!("Configuration reload failed, running configuration kept [{}]",watch::describe_error(&e));
+                                cmd.send_response(json!({ "result": false, "data": e.to_string() }).to_string())
+                            }
+                        }
                     },
                     VpiCommandBody::Signal(_) => {
                         let _=cmd.send_ok();
+                        audit.record(&cmd);
+                        info!("Graceful exit poweroff_onexit:{}",cfg.poweroff_onexit);
+                        if cfg.poweroff_onexit {
+                            let _=vpi.shutdown().cmd();
+                        } else {
+                            let _=vpi.init().cmd();
+                        }
                         return Ok(RET_CODE_EXIT);
                     },
                     VpiCommandBody::Basic(ref basic_command) => {
+                        let fan_value=vpi.get_fan_value();
                         match vpi.run(basic_command,true) {
                             Ok(output) =>  { 
                                 cmd.send_output(&output);
                                 info!("Command executed:{}",output);
+                                if vpi.get_fan_value() != fan_value {
+                                    events.publish(VpiEvent::Fan { duty: vpi.get_fan_value() });
+                                }
                                 if let VpiCmd::Wdg(wdg) = basic_command  {
-                                    if *wdg == 0u8 || !cfg.watchdog_autofeed {
-                                        info!("Disabling watchdog autofeed");
-                                        auto_feed=never::<Instant>();
-                                    } else {
-                                        info!("Enable watchdog autofeed to {} ms",(cfg.watchdog as u64*1000u64)/2u64);
-                                        auto_feed=tick(Duration::from_millis( (cfg.watchdog as u64*1000u64)/2u64 ) );
-                                    }
+                                    auto_feed=autofeed_timer(*wdg,&cfg);
                                 }
                             },
                             Err(e) => error!("Command {:?} failed",e)
                         }
                     },
-                    VpiCommandBody::SetKey(ref key, ref value) => {
-                        info!("Key '{}' storage set to '{}'",key,value);
-                        key_storage.insert(key.clone(),value.clone());
-                        cmd.send_ok()
+                    VpiCommandBody::SetKey(ref key, ref value, ttl) => {
+                        match key_storage.set(key,value,ttl) {
+                            Ok(changed) => {
+                                info!("Key '{}' storage set to '{}' ttl:{:?}",key,value,ttl);
+                                cmd.send_ok();
+                                events.publish(VpiEvent::Key { key: key.clone(), value: Some(value.clone()) });
+                                if changed {
+                                    let _=engine.run_key_rules(key,Some(value),&last_status,&last_stats,&key_storage,vpi.get_fan_value());
+                                }
+                            },
+                            Err(e) => {
+                                warn!("Key '{}' not set: {}",key,e);
+                                cmd.send_response(json!({ "result": false, "data": e }).to_string())
+                            }
+                        }
+                    },
+                    VpiCommandBody::DelKey(ref key) => {
+                        if key_storage.del(key) {
+                            info!("Key '{}' deleted",key);
+                            cmd.send_ok();
+                            events.publish(VpiEvent::Key { key: key.clone(), value: None });
+                            let _=engine.run_key_rules(key,None,&last_status,&last_stats,&key_storage,vpi.get_fan_value());
+                        } else {
+                            warn!("Key '{}' not found",key);
+                            cmd.send_error()
+                        }
+                    },
+                    VpiCommandBody::Keys(ref prefix) => {
+                        let js=json!({
+                            "result": true,
+                            "data": key_storage.keys(prefix)
+                        });
+                        cmd.send_response(js.to_string())
                     },
                     VpiCommandBody::GetKey(ref key) => {
-                        if key_storage.contains_key(key) {
-                            let val=key_storage.get(key).unwrap();
+                        if let Some(val) = key_storage.get(key) {
                             info!("Key '{}' storage retrieved with value '{}'",key,val);
                             let js=json!({
                                 "result":true,
@@ -253,8 +505,35 @@ fn serve(cfg_file : &PathBuf,
                             cmd.send_error()
                         }
                     },
+                    VpiCommandBody::Thermal => {
+                        let js=json!({
+                            "result": true,
+                            "data": VpiFanConfig::thermal(&fan_controller,vpi.get_fan_value())
+                        });
+                        cmd.send_response(js.to_string())
+                    },
+                    VpiCommandBody::Metrics => {
+                        let js=json!({
+                            "result": true,
+                            "data": {
+                                "status": last_status,
+                                "stats": vpi.get_stats(),
+                                "thermal": VpiFanConfig::thermal(&fan_controller,vpi.get_fan_value()),
+                                "engine": engine.metrics(),
+                            }
+                        });
+                        cmd.send_response(js.to_string())
+                    },
+                    VpiCommandBody::Subscribe => {
+                        if let VpiOrigin::Socket { .. } = cmd.origin {
+                            cmd.send_response(r#"{"result":true,"data":"subscribed"}"#.to_string())
+                        } else {
+                            cmd.send_response(json!({ "result": false, "data": "subscribe is only available on the socket" }).to_string())
+                        }
+                    },
                     VpiCommandBody::Exit(reboot) => {
                         cmd.send_ok();
+                        audit.record(&cmd);
                         info!("Exit command reboot:{}",reboot);
                         if reboot {
                             let _=vpi.init().cmd();
@@ -264,8 +543,12 @@ fn serve(cfg_file : &PathBuf,
                         return Ok(RET_CODE_EXIT);
                     },
                 }
+                audit.record(&cmd);
             } // match 
         } //Select   
+        for rec in engine.take_audits() {
+            audit.record_action(&rec);
+        }
     }//loop
     //Ok(0i32)
 }
\ No newline at end of file
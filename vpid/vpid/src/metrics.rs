@@ -0,0 +1,106 @@
+//! Prometheus metrics
+//! Renders the response of the `metrics` command in the Prometheus text format,
+//! served by the HTTP server on `GET /metrics`.
+
+use serde_json::Value;
+use std::fmt::Write;
+
+/// Content type of the text exposition format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Metrics taken from a JSON field: (name, kind, help, section, field)
+const METRICS: [(&str,&str,&str,&str,&str); 21] = [
+    ("vpi_rpm",                   "gauge",  "Fan speed in rpm",                    "status", "rpm"),
+    ("vpi_out_value",             "gauge",  "Open collector output (1 on)",        "status", "out_value"),
+    ("vpi_running",               "gauge",  "Board in running state",              "status", "is_running"),
+    ("vpi_wdg_enabled",           "gauge",  "Watchdog enabled",                    "status", "is_wdg_enabled"),
+    ("vpi_wake_enabled",          "gauge",  "Wake by timer enabled",               "status", "is_wake_enabled"),
+    ("vpi_wake_irq_enabled",      "gauge",  "Wake by IRQ enabled",                 "status", "is_wake_irq_enabled"),
+    ("vpi_retries_total",         "counter","I2C transaction retries",             "stats",  "retries"),
+    ("vpi_recovers_total",        "counter","I2C connection recoveries",           "stats",  "recovers"),
+    ("vpi_i2c_errors_total",      "counter","I2C errors reported by the board",    "stats",  "i2c_errors"),
+    ("vpi_crc_errors_total",      "counter","Configuration CRC resynchronizations","stats",  "crc_errors"),
+    ("vpi_status_checks_total",   "counter","Status reads",                        "stats",  "status_checks"),
+    ("vpi_temperature_celsius",   "gauge",  "SoC temperature",                     "thermal","temp"),
+    ("vpi_fan_target_celsius",    "gauge",  "Target temperature of the PI fan regulation","thermal","target"),
+    ("vpi_fan_duty",              "gauge",  "Fan duty 0-255",                      "thermal","duty"),
+    ("vpi_fan_pi_integral",       "gauge",  "Integral term of the PI fan regulation","thermal","pi_sum"),
+    ("vpid_lua_jobs",             "gauge",  "Running Lua scripts & mini services", "engine", "lua_jobs"),
+    ("vpid_shell_jobs",           "gauge",  "Running asynchronous shell scripts",  "engine", "shell_jobs"),
+    ("vpid_rule_checks_total",    "counter","Checks of the status rules",          "engine", "rule_checks"),
+    ("vpid_up",                   "gauge",  "vpid is running",                     "",       ""),
+    ("vpid_info",                 "gauge",  "vpid version",                        "",       ""),
+    ("vpid_rule_fires_total",     "counter","Times each rule matched",             "engine", "rule_fires"),
+];
+
+/// Number value of a JSON field, booleans as 0/1
+fn number(v: &Value) -> Option<f64> {
+    match v {
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+/// Escape a label value
+fn label(s: &str) -> String {
+    s.replace('\\',"\\\\").replace('"',"\\\"").replace('\n',"\\n")
+}
+
+/// Render the `data` of the metrics command
+pub fn render(data: &Value, version: &str) -> String {
+    let mut out = String::new();
+    for (name,kind,help,section,field) in METRICS.iter() {
+        let _=writeln!(out,"# HELP {} {}",name,help);
+        let _=writeln!(out,"# TYPE {} {}",name,kind);
+        match *name {
+            "vpid_up" => { let _=writeln!(out,"{} 1",name); },
+            "vpid_info" => { let _=writeln!(out,"{}{{version=\"{}\"}} 1",name,label(version)); },
+            "vpid_rule_fires_total" => {
+                if let Some(rules) = data[section][field].as_object() {
+                    for (rule,v) in rules {
+                        if let Some(n) = number(v) {
+                            let _=writeln!(out,"{}{{rule=\"{}\"}} {}",name,label(rule),n);
+                        }
+                    }
+                }
+            },
+            _ => {
+                if let Some(n) = number(&data[section][field]) {
+                    let _=writeln!(out,"{} {}",name,n);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn render_metrics() {
+        let data = json!({
+            "status": { "rpm": 1200, "is_running": true, "out_value": false },
+            "stats": { "retries": 3 },
+            "thermal": { "temp": 45.5, "target": null },
+            "engine": { "lua_jobs": 1, "rule_fires": { "hot \"fan\"": 2 } },
+        });
+        let out = render(&data, "0.1.1");
+        assert!(out.contains("# HELP vpi_rpm Fan speed in rpm\n# TYPE vpi_rpm gauge\nvpi_rpm 1200\n"));
+        assert!(out.contains("\nvpi_running 1\n"));
+        assert!(out.contains("\nvpi_out_value 0\n"));
+        assert!(out.contains("\nvpi_retries_total 3\n"));
+        assert!(out.contains("\nvpi_temperature_celsius 45.5\n"));
+        assert!(out.contains("\nvpid_lua_jobs 1\n"));
+        assert!(out.contains("\nvpid_up 1\n"));
+        assert!(out.contains("\nvpid_info{version=\"0.1.1\"} 1\n"));
+        assert!(out.contains("\nvpid_rule_fires_total{rule=\"hot \\\"fan\\\"\"} 2\n"));
+        // Missing values have no sample
+        assert!(!out.contains("\nvpi_fan_target_celsius "));
+        assert!(!out.contains("\nvpi_crc_errors_total "));
+        assert_eq!(out.lines().filter(|l| l.starts_with("# TYPE")).count(), METRICS.len());
+    }
+}
@@ -0,0 +1,291 @@
+//! MQTT integration
+//! Connects to a broker and publishes under `prefix`:
+//!  <prefix>/availability  online/offline presence (retained, offline is the last will)
+//!  <prefix>/status        board status JSON (retained)
+//!  <prefix>/stats         driver statistics JSON (retained)
+//!  <prefix>/temp          SoC temperature in ºC (retained)
+//!  <prefix>/fan           fan duty 0-255 (retained)
+//!  <prefix>/event/<name>  events (button, irq, rpm, ...) as JSON messages
+//! Commands in text form (`fan 100`, `setkey k v`) are received on `<prefix>/cmd` and
+//! the response envelope, with the command in `"cmd"`, published on `<prefix>/cmd/response`.
+//! Commands are authorized as principal `mqtt`, only queries without acl rules (see `acl`).
+//! With `discovery`, Home Assistant discovery configs are published (see `hass`).
+//! Test with a local broker: `mosquitto -v` & `mosquitto_sub -v -t 'vpid/#'`.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use serde_json::{json,Value};
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{Sender,unbounded,tick};
+use rumqttc::{Client,MqttOptions,LastWill,QoS,Event,Packet};
+use crate::cmd::{VpiCommand,VpiOrigin,exec_command,exec_command_json};
+use crate::events::{EventBus,VpiEvent};
+use crate::hass;
+
+/// Wait before retrying after a connection error
+const MQTT_RETRY: Duration = Duration::from_secs(5);
+/// Requests queued to the broker connection
+const MQTT_QUEUE: usize = 32;
+
+/// MQTT configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiMqttConfig {
+    /// Broker host
+    pub host: String,
+    /// Broker port
+    pub port: u16,
+    /// Client id
+    pub client_id: String,
+    /// Broker user
+    pub username: Option<String>,
+    /// Broker password
+    pub password: Option<String>,
+    /// Prefix of all topics
+    pub prefix: String,
+    /// Period in seconds to publish status, stats, temperature & fan
+    pub interval: u32,
+    /// Keep alive in seconds
+    pub keep_alive: u16,
+    /// Publish Home Assistant discovery configs
+    pub discovery: bool,
+    /// Topic prefix of Home Assistant discovery
+    pub discovery_prefix: String,
+}
+
+impl Default for VpiMqttConfig {
+    fn default() -> Self {
+        VpiMqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "vpid".to_string(),
+            username: None,
+            password: None,
+            prefix: "vpid".to_string(),
+            interval: 10,
+            keep_alive: 30,
+            discovery: false,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+impl VpiMqttConfig {
+    /// Full topic name
+    pub fn topic(&self, t: &str) -> String {
+        format!("{}/{}",self.prefix.trim_end_matches('/'),t)
+    }
+}
+
+/// Jobs of the command thread
+enum MqttJob {
+    /// Command received
+    Command(String),
+    /// Publish Home Assistant discovery
+    Discovery,
+}
+
+/// Connected MQTT client with the daemon topics
+#[derive(Clone)]
+pub struct VpiMqtt {
+    pub cfg: VpiMqttConfig,
+    pub client: Client,
+}
+
+impl VpiMqtt {
+    /// Publish without blocking, messages are dropped if the queue is full
+    pub fn publish(&self, topic: &str, retain: bool, payload: String) {
+        if let Err(e) = self.client.try_publish(self.cfg.topic(topic), QoS::AtLeastOnce, retain, payload) {
+            debug!("MQTT publish to {} dropped: {}",topic,e);
+        }
+    }
+    /// Origin of the commands sent by MQTT
+    fn origin(&self) -> VpiOrigin {
+        VpiOrigin::Mqtt(format!("{}:{}",self.cfg.host,self.cfg.port))
+    }
+    /// Publish the state of the board
+    fn publish_state(&self, command_sender: &Sender<VpiCommand>) {
+        for (cmd,topic) in [("status","status"),("stats","stats")].iter() {
+            match exec_command_json(&cmd.to_string(), command_sender, &self.origin()) {
+                Ok(v) if v["result"].as_bool().unwrap_or(false) => self.publish(topic, true, v["data"].to_string()),
+                Ok(v) => warn!("MQTT {} command failed {}",cmd,v),
+                Err(e) => warn!("MQTT {} command failed {}",cmd,e),
+            }
+        }
+        if let Ok(v) = exec_command_json(&"thermal".to_string(), command_sender, &self.origin()) {
+            if !v["data"]["temp"].is_null() {
+                self.publish("temp", true, v["data"]["temp"].to_string());
+            }
+            if v["data"]["duty"].is_number() {
+                self.publish("fan", true, v["data"]["duty"].to_string());
+            }
+        }
+    }
+    /// Publish Home Assistant discovery configs. The UUID is read once from the board.
+    fn publish_discovery(&self, uuid: &mut Option<String>, command_sender: &Sender<VpiCommand>) {
+        if uuid.is_none() {
+            match exec_command_json(&"uuid".to_string(), command_sender, &self.origin()) {
+                Ok(v) if v["data"].is_string() => *uuid = v["data"].as_str().map(|u| u.to_string()),
+                Ok(v) => warn!("MQTT discovery failed, no board UUID {}",v),
+                Err(e) => warn!("MQTT discovery failed, no board UUID {}",e),
+            }
+        }
+        if let Some(ref u) = uuid {
+            info!("MQTT Home Assistant discovery of board {}",u);
+            for (topic,conf) in hass::discovery_configs(&self.cfg, u) {
+                if let Err(e) = self.client.try_publish(topic.clone(), QoS::AtLeastOnce, true, conf.to_string()) {
+                    warn!("MQTT discovery {} dropped: {}",topic,e);
+                }
+            }
+        }
+    }
+    /// Publish an event
+    fn publish_event(&self, ev: &VpiEvent) {
+        if let VpiEvent::Fan { duty } = ev {
+            self.publish("fan", true, duty.to_string());
+        }
+        self.publish(&format!("event/{}",ev.name()), false, json!(ev).to_string());
+    }
+}
+
+/// Connect to the broker and start the MQTT threads
+pub fn run_mqtt(cfg: &VpiMqttConfig, command_sender_orig: &Sender<VpiCommand>, events: &EventBus) -> VpiMqtt {
+    let mut opts = MqttOptions::new(cfg.client_id.clone(), cfg.host.clone(), cfg.port);
+    opts.set_keep_alive(Duration::from_secs(std::cmp::max(5,cfg.keep_alive) as u64));
+    opts.set_last_will(LastWill::new(cfg.topic("availability"), "offline", QoS::AtLeastOnce, true));
+    if let Some(ref user) = cfg.username {
+        opts.set_credentials(user.clone(), cfg.password.clone().unwrap_or_default());
+    }
+    let (client, mut connection) = Client::new(opts, MQTT_QUEUE);
+    let mqtt = VpiMqtt { cfg: cfg.clone(), client };
+
+    // Connection thread: drives the connection and forwards received commands
+    let (job_sender,job_receiver) = unbounded::<MqttJob>();
+    let conn_mqtt = mqtt.clone();
+    thread::spawn(move || {
+        let cmd_topic = conn_mqtt.cfg.topic("cmd");
+        let hass_status = format!("{}/status",conn_mqtt.cfg.discovery_prefix);
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT connected to {}:{}",conn_mqtt.cfg.host,conn_mqtt.cfg.port);
+                    conn_mqtt.publish("availability", true, "online".to_string());
+                    if let Err(e) = conn_mqtt.client.try_subscribe(cmd_topic.clone(), QoS::AtLeastOnce) {
+                        error!("MQTT subscription to {} failed: {}",cmd_topic,e);
+                    }
+                    if conn_mqtt.cfg.discovery {
+                        let _=conn_mqtt.client.try_subscribe(hass_status.clone(), QoS::AtLeastOnce);
+                        let _=job_sender.send(MqttJob::Discovery);
+                    }
+                },
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == cmd_topic => {
+                    let _=job_sender.send(MqttJob::Command(String::from_utf8_lossy(&p.payload).trim().to_string()));
+                },
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == hass_status && &p.payload[..] == b"online" => {
+                    let _=job_sender.send(MqttJob::Discovery);
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    warn!("MQTT connection to {}:{} failed: {}",conn_mqtt.cfg.host,conn_mqtt.cfg.port,e);
+                    thread::sleep(MQTT_RETRY);
+                }
+            }
+        }
+    });
+
+    // Command thread: runs the commands received & discovery
+    let cmd_mqtt = mqtt.clone();
+    let command_sender = command_sender_orig.clone();
+    thread::spawn(move || {
+        let mut uuid: Option<String> = None;
+        for job in job_receiver.iter() {
+            let cmd = match job {
+                MqttJob::Command(cmd) => cmd,
+                MqttJob::Discovery => { cmd_mqtt.publish_discovery(&mut uuid, &command_sender); continue; },
+            };
+            info!("MQTT command '{}'",cmd);
+            let mut resp: Value = match exec_command(&cmd, &command_sender, &cmd_mqtt.origin()) {
+                Ok(r) => serde_json::from_str(&r).unwrap_or_else(|_| json!({ "result": false, "data": r })),
+                Err(e) => json!({ "result": false, "data": e.to_string() }),
+            };
+            resp["cmd"] = Value::from(cmd);
+            cmd_mqtt.publish("cmd/response", false, resp.to_string());
+        }
+    });
+
+    // Publisher thread: periodic state & events
+    let pub_mqtt = mqtt.clone();
+    let command_sender = command_sender_orig.clone();
+    let event_receiver = events.subscribe();
+    let period = tick(Duration::from_secs(std::cmp::max(1,cfg.interval) as u64));
+    thread::spawn(move || {
+        pub_mqtt.publish_state(&command_sender);
+        loop {
+            select! {
+                recv(period) -> _ => pub_mqtt.publish_state(&command_sender),
+                recv(event_receiver) -> ev => match ev {
+                    Ok(ev) => pub_mqtt.publish_event(&ev),
+                    Err(_) => break,
+                },
+            }
+        }
+    });
+    mqtt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    #[test]
+    fn topics() {
+        let cfg = VpiMqttConfig { prefix: "home/vpid/".to_string(), ..Default::default() };
+        assert_eq!(cfg.topic("cmd/response"), "home/vpid/cmd/response");
+    }
+
+    /// Needs a broker on localhost:1883 (`mosquitto`), run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn broker_commands() {
+        if TcpStream::connect("127.0.0.1:1883").is_err() {
+            eprintln!("No MQTT broker on localhost:1883, test skipped");
+            return;
+        }
+        let prefix = format!("vpid-test-{}",std::process::id());
+        let cfg = VpiMqttConfig { client_id: format!("{}-daemon",prefix), prefix: prefix.clone(), ..Default::default() };
+        let (command_sender,command_receiver) = unbounded::<VpiCommand>();
+        thread::spawn(move || { // Daemon answering every command
+            for cmd in command_receiver.iter() {
+                cmd.send_response(r#"{"result":true,"data":{}}"#.to_string());
+            }
+        });
+        let events = EventBus::new();
+        let _mqtt = run_mqtt(&cfg, &command_sender, &events);
+
+        let (client, mut connection) = Client::new(MqttOptions::new(format!("{}-test",prefix), "127.0.0.1", 1883), 10);
+        client.subscribe(format!("{}/#",prefix), QoS::AtLeastOnce).unwrap();
+        let started = Instant::now();
+        let (mut online,mut response) = (false,None);
+        for notification in connection.iter() {
+            if started.elapsed() > Duration::from_secs(10) {
+                break;
+            }
+            match notification {
+                Ok(Event::Incoming(Packet::SubAck(_))) => client.publish(format!("{}/cmd",prefix), QoS::AtLeastOnce, false, "status").unwrap(),
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == format!("{}/availability",prefix) => online = &p.payload[..] == b"online",
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == format!("{}/cmd/response",prefix) => {
+                    response = serde_json::from_slice::<Value>(&p.payload).ok();
+                    break;
+                },
+                Ok(_) => {},
+                Err(e) => panic!("MQTT test client failed: {}",e),
+            }
+        }
+        assert!(online);
+        assert_eq!(response, Some(json!({"result":true,"data":{},"cmd":"status"})));
+    }
+}
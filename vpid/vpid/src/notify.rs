@@ -0,0 +1,235 @@
+//! Webhooks
+//! HTTP POST of a JSON payload with the board status & stats, SoC temperature, hostname
+//! and time: `Webhook` rules post when they fire and the global `notify` targets post the
+//! daemon events. Requests are queued to a single worker thread so the engine is never
+//! blocked, posts are dropped while the queue is full. A failed request (connection error or
+//! 5xx) is retried `retries` times waiting `backoff` ms, doubled on each retry.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use serde_json::{json,Value};
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::thread;
+use std::time::Duration;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64,Ordering};
+use chrono::Local;
+use crossbeam_channel::{Sender,TrySendError,bounded};
+use crate::cmd::{VpiCommand,VpiOrigin,exec_command_json};
+use crate::events::EventBus;
+
+/// Posts waiting the worker
+const QUEUE_SIZE: usize = 32;
+
+/// Events not posted by the notify targets without `events`, too frequent
+const NOISY_EVENTS: [&str; 2] = ["rpm","fan"];
+
+/// Queue of the worker, started by the first post
+static QUEUE: OnceLock<Sender<Post>> = OnceLock::new();
+
+/// Posts dropped with the queue full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Webhook target
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq,Eq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiWebhook {
+    /// URL of the POST, http:// or https://
+    pub url: String,
+    /// Extra headers, e.g. Authorization
+    pub headers: BTreeMap<String,String>,
+    /// Request timeout in seconds
+    pub timeout: u32,
+    /// Retries of a failed request
+    pub retries: u32,
+    /// Wait in ms before the first retry, doubled on each retry
+    pub backoff: u32,
+    /// Notify targets: events posted (button, irq, rule...), all but rpm & fan if empty
+    pub events: Vec<String>,
+}
+
+impl Default for VpiWebhook {
+    fn default() -> Self {
+        VpiWebhook {
+            url: String::new(),
+            headers: BTreeMap::new(),
+            timeout: 5,
+            retries: 3,
+            backoff: 1000,
+            events: vec!(),
+        }
+    }
+}
+
+impl VpiWebhook {
+    /// Configuration problems
+    pub fn validate(&self, name: &str) -> Vec<String> {
+        let mut errors=vec!();
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            errors.push(format!("{} url '{}' must start with http:// or https://",name,self.url));
+        }
+        if self.timeout == 0 {
+            errors.push(format!("{} timeout must be > 0 seconds",name));
+        }
+        for ev in self.events.iter().filter(|e| !crate::events::EVENT_NAMES.contains(&e.as_str())) {
+            errors.push(format!("{} unknown event {}",name,ev));
+        }
+        errors
+    }
+    /// Notify targets: the event is posted
+    fn wants(&self, event: &str) -> bool {
+        if self.events.is_empty() {
+            !NOISY_EVENTS.contains(&event)
+        } else {
+            self.events.iter().any(|e| e == event)
+        }
+    }
+    /// Queue the POST of the payload, dropped if the queue is full
+    pub fn post(&self, name: &str, payload: Value) {
+        let queue=QUEUE.get_or_init(|| {
+            let (sender,receiver)=bounded::<Post>(QUEUE_SIZE);
+            thread::spawn(move || {
+                for post in receiver.iter() {
+                    post.send();
+                }
+            });
+            sender
+        });
+        enqueue(queue, Post { hook: self.clone(), name: name.to_string(), payload });
+    }
+}
+
+/// Queued POST
+struct Post {
+    hook: VpiWebhook,
+    name: String,
+    payload: Value,
+}
+
+impl Post {
+    /// POST the payload, retrying failed requests
+    fn send(&self) {
+        let hook=&self.hook;
+        let mut wait=hook.backoff as u64;
+        for attempt in 0..=hook.retries {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(wait));
+                wait*=2;
+            }
+            let mut req=ureq::post(&hook.url).timeout(Duration::from_secs(hook.timeout as u64));
+            for (k,v) in hook.headers.iter() {
+                req=req.set(k,v);
+            }
+            match req.send_json(self.payload.clone()) {
+                Ok(res) => {
+                    info!("Webhook [{}] posted to {} status:{}",self.name,hook.url,res.status());
+                    return;
+                },
+                Err(ureq::Error::Status(code,_)) if code < 500 => {
+                    warn!("Webhook [{}] rejected by {} status:{}",self.name,hook.url,code);
+                    return;
+                },
+                Err(e) => warn!("Webhook [{}] post to {} failed, attempt {} of {}: {}",self.name,hook.url,attempt+1,hook.retries+1,e),
+            }
+        }
+        error!("Webhook [{}] dropped after {} attempts",self.name,hook.retries+1);
+    }
+}
+
+/// Queue a post without blocking, false if dropped
+fn enqueue(queue: &Sender<Post>, post: Post) -> bool {
+    match queue.try_send(post) {
+        Ok(()) => true,
+        Err(TrySendError::Full(post)) => {
+            let dropped=DROPPED.fetch_add(1,Ordering::Relaxed)+1;
+            warn!("Webhook queue full, [{}] post to {} dropped ({} dropped)",post.name,post.hook.url,dropped);
+            false
+        },
+        Err(TrySendError::Disconnected(post)) => {
+            error!("Webhook worker stopped, [{}] post to {} dropped",post.name,post.hook.url);
+            false
+        },
+    }
+}
+
+/// Hostname of the system
+pub fn hostname() -> String {
+    read_to_string("/proc/sys/kernel/hostname").map(|h| h.trim().to_string()).unwrap_or_default()
+}
+
+/// Payload of a webhook: `fields` plus hostname, time, temperature in °C, status & stats
+pub fn payload(fields: Value, temp: Option<f32>, status: Value, stats: Value) -> Value {
+    let mut js=json!({
+        "hostname": hostname(),
+        "time": Local::now().to_rfc3339(),
+        "temp": temp,
+        "status": status,
+        "stats": stats,
+    });
+    if let (Some(js),Value::Object(fields)) = (js.as_object_mut(),fields) {
+        js.extend(fields);
+    }
+    js
+}
+
+/// Start the thread posting the events to the notify targets
+pub fn run_notify(targets: &[VpiWebhook], command_sender_orig: &Sender<VpiCommand>, events: &EventBus) {
+    if targets.is_empty() {
+        return;
+    }
+    info!("Notify started, {} targets",targets.len());
+    let targets=targets.to_vec();
+    let command_sender=command_sender_orig.clone();
+    let event_receiver=events.subscribe();
+    thread::spawn(move || {
+        let data = |cmd: &str| match exec_command_json(&cmd.to_string(), &command_sender, &VpiOrigin::Daemon) {
+            Ok(v) => v["data"].clone(),
+            Err(e) => { warn!("Notify {} command failed {}",cmd,e); Value::Null },
+        };
+        for ev in event_receiver.iter() {
+            let hooks: Vec<&VpiWebhook> = targets.iter().filter(|t| t.wants(ev.name())).collect();
+            if hooks.is_empty() {
+                continue;
+            }
+            let temp=data("thermal")["temp"].as_f64().map(|t| t as f32);
+            let js=payload(json!({ "event": ev.name(), "data": ev }), temp, data("status"), data("stats"));
+            for hook in hooks {
+                hook.post(ev.name(), js.clone());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(events: &[&str]) -> VpiWebhook {
+        VpiWebhook { url: "http://127.0.0.1:9/hook".to_string(), events: events.iter().map(|e| e.to_string()).collect(), ..Default::default() }
+    }
+
+    #[test]
+    fn event_filter() {
+        let all = hook(&[]);
+        assert!(all.wants("button") && all.wants("rule") && all.wants("key"));
+        assert!(!all.wants("rpm") && !all.wants("fan"));
+        let some = hook(&["rpm","irq"]);
+        assert!(some.wants("rpm") && some.wants("irq"));
+        assert!(!some.wants("button"));
+    }
+
+    #[test]
+    fn full_queue_drops() {
+        let (sender,receiver) = bounded::<Post>(2);
+        let post = |n: &str| Post { hook: hook(&[]), name: n.to_string(), payload: json!({}) };
+        assert!(enqueue(&sender, post("a")));
+        assert!(enqueue(&sender, post("b")));
+        assert!(!enqueue(&sender, post("c")));
+        let names: Vec<String> = receiver.try_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!("a","b"));
+        drop(receiver);
+        assert!(!enqueue(&sender, post("d")));
+    }
+}
@@ -1,47 +1,214 @@
-///! Socket control module
-///! The main mechanims to
+//! Socket control module
+//! The main mechanims to control vpid from other processes.
+//! Each connection is a session that accepts many newline-delimited requests
+//! until `quit` or EOF. A request is either a plain text command (`status`) or
+//! a JSON object `{"id":<any>,"cmd":"status"}`; the `id` is echoed in the response.
+//! Every response is a JSON object in a single line.
+//! `subscribe [event]...` turns the session in to a stream of JSON events, one per
+//! line, filtered by event name (all if none). It ends with `quit` or when closed.
+//! Commands, `subscribe` included, are authorized by the daemon with the peer
+//! credentials of the client (see `acl`). Each client is served by its own thread.
+//! The socket file is created with mode 0600 and then set as configured (0666 by default).
 
-use crate::error::{Result,ResultExt,SockBind};
+use crate::error::{Result,ResultExt,SockBind,Error};
+use crate::acl::{VpiSocketConfig,Peer};
 use std::thread;
 use std::thread::JoinHandle;
-use std::os::unix::net::{UnixListener};
-use crossbeam_channel::{Sender,bounded};
+use std::os::unix::net::{UnixListener,UnixStream};
+use crossbeam_channel::{Sender,Receiver,RecvTimeoutError};
+use std::time::Duration;
 use std::path::PathBuf;
-use crate::cmd::{VpiCommand,exec_command};
-use std::io::{Write,BufRead,BufReader};
+use crate::cmd::{VpiCommand,VpiOrigin,exec_command};
+use crate::events::{EventBus,VpiEvent,EVENT_NAMES};
+use std::io::{Write,BufRead,BufReader,ErrorKind};
 use std::net::Shutdown;
+use serde_json::{json,Value};
+use nix::sys::stat::{umask,Mode};
 
+/// Command that ends a session
+const SESSION_QUIT: &str = "quit";
+/// Command that streams events
+const SESSION_SUBSCRIBE: &str = "subscribe";
+/// Time between checks of a subscribed client
+const EVENT_CHECK_TIME: Duration = Duration::from_secs(1);
+/// Idle time before an inactive session is closed
+const SESSION_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Max time to write a response to a client
+const SESSION_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// A parsed request line
+struct Request {
+    id: Option<Value>,
+    cmd: String,
+}
+
+impl Request {
+    /// Parse a request line. JSON requests must have a `cmd` string.
+    /// Errors carry the id of the request, if found, to echo it.
+    fn parse(line:&str) -> std::result::Result<Self,(Option<Value>,String)> {
+        if line.starts_with('{') {
+            let obj: Value = serde_json::from_str(line).map_err(|e| (None,format!("Invalid JSON request: {}",e)))?;
+            let id = obj.get("id").cloned();
+            match obj.get("cmd").and_then(|c| c.as_str()) {
+                Some(cmd) => Ok(Request { id, cmd: cmd.trim().to_string() }),
+                None => Err((id,"JSON request without cmd".to_string())),
+            }
+        } else {
+            Ok(Request { id: None, cmd: line.to_string() })
+        }
+    }
+}
+
+/// Build the response line adding the request id if any
+fn response_line(resp: &str, id: &Option<Value>) -> String {
+    let mut obj: Value = serde_json::from_str(resp).unwrap_or_else(|_| json!({ "result": false, "data": resp }));
+    if let (Some(id), Some(o)) = (id, obj.as_object_mut()) {
+        o.insert("id".to_string(), id.clone());
+    }
+    format!("{}\n",obj)
+}
+
+fn error_response(msg: &str) -> String {
+    json!({ "result": false, "data": msg }).to_string()
+}
+
+/// Parse event filters of subscribe
+fn parse_filters(cmd: &str) -> std::result::Result<Vec<String>,String> {
+    let filters: Vec<String> = cmd.split_whitespace().skip(1).map(|f| f.to_lowercase()).collect();
+    match filters.iter().find(|f| !EVENT_NAMES.contains(&f.as_str())) {
+        Some(f) => Err(format!("Unknown event '{}', valid events: {}",f,EVENT_NAMES.join(","))),
+        None => Ok(filters),
+    }
+}
+
+/// Ask the daemon to authorize a subscription, returns if granted & the response
+fn exec_subscribe(cmd: &str, command_sender: &Sender<VpiCommand>, origin: &VpiOrigin) -> std::result::Result<(bool,String),String> {
+    let resp = exec_command(&cmd.to_string(), command_sender, origin).map_err(|e| e.to_string())?;
+    let granted = serde_json::from_str::<Value>(&resp).ok().and_then(|v| v["result"].as_bool()).unwrap_or(false);
+    Ok((granted,resp))
+}
+
+/// Stream events to a subscribed client until quit, EOF or write failure
+fn stream_events(reader: &mut BufReader<&UnixStream>, writer: &mut UnixStream, events: Receiver<VpiEvent>, filters: &[String]) {
+    if reader.get_ref().set_read_timeout(Some(Duration::from_millis(1))).is_err() {
+        return;
+    }
+    loop {
+        match events.recv_timeout(EVENT_CHECK_TIME) {
+            Ok(ev) => {
+                if !filters.is_empty() && !filters.iter().any(|f| f==ev.name()) { continue; }
+                let line=format!("{}\n",json!(ev));
+                if writer.write_all(line.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                    return;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => { // Check if client is still there
+                let mut line = String::new();
+                match reader.read_line(&mut line) {
+                    Ok(0) => return, // EOF
+                    Ok(_) if line.trim().to_lowercase()==SESSION_QUIT => return,
+                    Ok(_) => {},
+                    Err(e) if e.kind()==ErrorKind::WouldBlock || e.kind()==ErrorKind::TimedOut => {},
+                    Err(_) => return,
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Serve a session until quit or EOF
+fn run_session(socket: UnixStream, command_sender: Sender<VpiCommand>, events: EventBus) {
+    let peer = match Peer::from_socket(&socket) {
+        Ok(p) => p,
+        Err(e) => { error!("Socket client rejected:{}",e); return; }
+    };
+    debug!("Socket session of {}",peer);
+    let exe = std::fs::read_link(format!("/proc/{}/exe",peer.pid)).map(|p| p.display().to_string()).unwrap_or_else(|_| "?".to_string());
+    let origin = VpiOrigin::Socket { peer, exe };
+    if let Err(e) = socket.set_read_timeout(Some(SESSION_READ_TIMEOUT)).and_then(|_| socket.set_write_timeout(Some(SESSION_WRITE_TIMEOUT))) {
+        error!("Socket session timeouts could not be set:{}",e);
+        return;
+    }
+    let mut writer = match socket.try_clone() {
+        Ok(w) => w,
+        Err(e) => { error!("Socket session could not be started:{}",e); return; }
+    };
+    let mut reader = BufReader::new(&socket);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => break, // EOF
+            Ok(_len) => {
+                let line=line.trim();
+                if line.is_empty() { continue; }
+                let mut subscribe = None;
+                let (id,resp,quit) = match Request::parse(line) {
+                    Ok(req) => {
+                        let verb = req.cmd.split_whitespace().next().unwrap_or("").to_lowercase();
+                        if verb == SESSION_QUIT {
+                            (req.id, r#"{"result":true}"#.to_string(), true)
+                        } else if verb == SESSION_SUBSCRIBE {
+                            match parse_filters(&req.cmd).and_then(|f| exec_subscribe(&req.cmd, &command_sender, &origin).map(|r| (f,r))) {
+                                Ok((filters,(granted,resp))) => {
+                                    if granted {
+                                        subscribe = Some(filters);
+                                    }
+                                    (req.id, resp, false)
+                                },
+                                Err(e) => (req.id, error_response(&e), false),
+                            }
+                        } else if req.cmd.is_empty() {
+                            (req.id, error_response("command len 0"), false)
+                        } else {
+                            match exec_command(&req.cmd, &command_sender, &origin) {
+                                Ok(val) => (req.id, val, false),
+                                Err(e)  => (req.id, error_response(&e.to_string()), false),
+                            }
+                        }
+                    },
+                    Err((id,e)) => (id, error_response(&e), false),
+                };
+                let receiver = subscribe.as_ref().map(|_| events.subscribe()); // Subscribe before ack to lose no event
+                if writer.write_all(response_line(&resp,&id).as_bytes()).is_err() || quit {
+                    break;
+                }
+                let _=writer.flush();
+                if let (Some(filters),Some(receiver)) = (subscribe,receiver) {
+                    stream_events(&mut reader, &mut writer, receiver, &filters);
+                    break;
+                }
+            },
+            Err(e) if e.kind()==ErrorKind::WouldBlock || e.kind()==ErrorKind::TimedOut => { debug!("Socket session idle, closed"); break; },
+            Err(e) => { error!("Socket server read failed:{}",e); break; }
+        }
+    }
+    let _=writer.flush();
+    let _=socket.shutdown(Shutdown::Both);
+}
 
-pub fn run_socket(sock:&PathBuf,command_sender_orig: &Sender<VpiCommand>) -> Result<JoinHandle<()>> {
-    let listener = UnixListener::bind(sock).context( SockBind { sock: sock } )?;
-    let (bc_sender,bc_receiver) = bounded::<String>(1); // Back channel for the response
+pub fn run_socket(sock:&PathBuf,command_sender_orig: &Sender<VpiCommand>,events_orig: &EventBus,cfg: &VpiSocketConfig) -> Result<JoinHandle<()>> {
+    // Only the owner can connect until owner, group & mode are set
+    let old_mask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(sock).context( SockBind { sock } );
+    umask(old_mask);
+    let listener = listener?;
+    if let Err(msg) = cfg.apply(sock) {
+        close_socket(sock);
+        return Err(Error::SockConfig { sock: sock.clone(), msg });
+    }
     let command_sender= command_sender_orig.clone(); // Clone the sender to move it to sock thread
+    let events= events_orig.clone();
     // spawn thread for socket server
     let handle = thread::spawn( move | | {
-        loop {
-            match listener.accept() {
-                Ok((mut socket, _)) => {
-                    let mut reader = BufReader::new(std::io::Read::by_ref(&mut socket));
-                    let mut request = String::new();
-                    match reader.read_line(&mut request) {
-                        Ok(_len) => {
-                            let request_trimmed=request.trim().to_string();
-                            if request_trimmed.len() >0 {
-                                // Process the request
-                                match exec_command(&request_trimmed, &command_sender, &bc_sender, &bc_receiver) {
-                                    Ok(val) => { let _=socket.write(val.as_bytes()); },
-                                    Err(e)  => { let _=socket.write(format!(r#"{{"result":false,"data":"{}"}}"#,e).as_bytes()); }
-                                }
-
-                            } else {
-                                let _=socket.write(r#"{"result":false,"data":"command len 0"}"#.as_bytes());
-                            }
-                        },
-                        Err(e) => error!("Socket server read failed:{}",e)
+        for conn in listener.incoming() {
+            match conn {
+                Ok(socket) => {
+                    let session_sender=command_sender.clone();
+                    let session_events=events.clone();
+                    if let Err(e) = thread::Builder::new().name("vpid-sock".into()).spawn(move || run_session(socket, session_sender, session_events)) {
+                        error!("Socket session thread failed:{}",e);
                     }
-                    let _=socket.flush();
-                    let _=socket.shutdown(Shutdown::Both);  
                 },
                 Err(e) => error!("accept function failed: {:?}", e),
             }
@@ -53,3 +220,50 @@ pub fn run_socket(sock:&PathBuf,command_sender_orig: &Sender<VpiCommand>) -> Res
 pub fn close_socket(socket:&PathBuf) {
     let _=std::fs::remove_file(socket);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn parse_requests() {
+        let req = Request::parse("status").unwrap();
+        assert_eq!((req.id,req.cmd.as_str()), (None,"status"));
+        let req = Request::parse(r#"{"id":"a1","cmd":" fan 128 "}"#).unwrap();
+        assert_eq!((req.id,req.cmd.as_str()), (Some(json!("a1")),"fan 128"));
+        assert_eq!(Request::parse(r#"{"id":1}"#).err(), Some((Some(json!(1)),"JSON request without cmd".to_string())));
+        let (id,err) = Request::parse(r#"{"id":1,"cmd":"#).err().unwrap_or_default();
+        assert!(id.is_none() && err.starts_with("Invalid JSON request"));
+    }
+
+    #[test]
+    fn response_ids() {
+        assert_eq!(response_line(r#"{"result":true}"#, &Some(json!(7))), "{\"id\":7,\"result\":true}\n");
+        assert_eq!(response_line("not json", &None), "{\"data\":\"not json\",\"result\":false}\n");
+        assert_eq!(parse_filters("subscribe Button rule").unwrap(), vec!("button","rule"));
+        assert!(parse_filters("subscribe nothing").is_err());
+    }
+
+    #[test]
+    fn session_requests() {
+        let (sender,receiver) = unbounded::<VpiCommand>();
+        thread::spawn(move || {
+            for cmd in receiver.iter() {
+                cmd.send_response(r#"{"result":true,"data":"ok"}"#.to_string());
+            }
+        });
+        let (client,server) = UnixStream::pair().unwrap();
+        let session = thread::spawn(move || run_session(server, sender, EventBus::new()));
+        let mut writer = client.try_clone().unwrap();
+        writer.write_all(b"status\n{\"id\":7,\"cmd\":\"status\"}\n{\"id\":8}\n\nquit\n").unwrap();
+        let lines: Vec<Value> = BufReader::new(&client).lines().map(|l| serde_json::from_str(&l.unwrap()).unwrap()).collect();
+        assert_eq!(lines, vec!(
+            json!({"result":true,"data":"ok"}),
+            json!({"result":true,"data":"ok","id":7}),
+            json!({"id":8,"result":false,"data":"JSON request without cmd"}),
+            json!({"result":true}),
+        ));
+        session.join().unwrap();
+    }
+}
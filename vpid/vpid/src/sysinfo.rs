@@ -0,0 +1,91 @@
+//! System information for the rule conditions
+//! Load averages, memory, root file system usage and uptime read from /proc & statvfs,
+//! throttling flags of the Raspberry Pi firmware. Values that can't be read are None (nil in Lua).
+
+use std::fs::read_to_string;
+use nix::sys::statvfs::statvfs;
+
+/// Load averages of 1, 5 & 15 minutes
+pub fn load_avg() -> Option<(f64,f64,f64)> {
+    let s=read_to_string("/proc/loadavg").ok()?;
+    let mut it=s.split_whitespace().map(|v| v.parse::<f64>().ok());
+    Some((it.next()??,it.next()??,it.next()??))
+}
+
+/// Total & available memory in kB
+pub fn memory() -> Option<(u64,u64)> {
+    let s=read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| s.lines().find(|l| l.starts_with(name)).and_then(|l| l.split_whitespace().nth(1)).and_then(|v| v.parse::<u64>().ok());
+    Some((field("MemTotal:")?,field("MemAvailable:")?))
+}
+
+/// Total & free (for users) space in MB of the file system of `path`
+pub fn disk(path: &str) -> Option<(u64,u64)> {
+    let st=statvfs(path).ok()?;
+    let mb = |blocks: u64| blocks*st.fragment_size() as u64/(1024*1024);
+    Some((mb(st.blocks() as u64),mb(st.blocks_available() as u64)))
+}
+
+/// Seconds since boot
+pub fn uptime() -> Option<f64> {
+    read_to_string("/proc/uptime").ok()?.split_whitespace().next()?.parse::<f64>().ok()
+}
+
+/// Throttling state of the Raspberry Pi firmware, as `vcgencmd get_throttled`
+const THROTTLED_PATH: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// Rule variables of the `get_throttled` bits
+pub const THROTTLED_FLAGS: [(&str,u32); 8] = [
+    ("under_voltage",0),
+    ("freq_capped",1),
+    ("throttling",2),
+    ("soft_temp_limit",3),
+    ("under_voltage_occurred",16),
+    ("freq_capped_occurred",17),
+    ("throttling_occurred",18),
+    ("soft_temp_limit_occurred",19),
+];
+
+/// Throttling bits, the file holds them in hex
+pub fn throttled() -> Option<u32> {
+    u32::from_str_radix(read_to_string(THROTTLED_PATH).ok()?.trim().trim_start_matches("0x"),16).ok()
+}
+
+/// Flags of the throttling bits by variable name
+pub fn throttled_flags(bits: u32) -> impl Iterator<Item=(&'static str,bool)> {
+    THROTTLED_FLAGS.iter().map(move |(name,bit)| (*name,bits & (1<<bit) != 0))
+}
+
+/// Used percentage of a total
+pub fn used_pct(total: u64, free: u64) -> f64 {
+    if total == 0 { 0.0 } else { (total.saturating_sub(free)) as f64*100.0/total as f64 }
+}
+
+/// System values of the rule conditions, read together
+#[derive(Debug,Clone)]
+pub struct SysInfo {
+    pub load: Option<(f64,f64,f64)>,
+    pub memory: Option<(u64,u64)>,
+    pub disk: Option<(u64,u64)>,
+    pub uptime: Option<f64>,
+    pub throttled: Option<u32>,
+}
+
+impl SysInfo {
+    /// Read the current values
+    pub fn read() -> Self {
+        SysInfo { load: load_avg(), memory: memory(), disk: disk("/"), uptime: uptime(), throttled: throttled() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled_bits() {
+        let flags: Vec<&str> = throttled_flags(0x50005).filter(|(_,on)| *on).map(|(name,_)| name).collect();
+        assert_eq!(flags, vec!("under_voltage","throttling","under_voltage_occurred","throttling_occurred"));
+        assert_eq!(throttled_flags(0).filter(|(_,on)| *on).count(), 0);
+    }
+}
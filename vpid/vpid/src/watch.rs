@@ -0,0 +1,114 @@
+//! Config file watcher
+//! Watches the config file, the directories of its `include` patterns and `dirs` with
+//! inotify. After the writes settle for `debounce` ms a `reload` command is sent, the
+//! daemon parses & validates the new config and applies it only if valid. The directory
+//! of the config file is watched to catch editors and tools that replace the file by a
+//! rename.
+
+use serde::Deserialize;
+use serde_piecewise_default::DeserializePiecewiseDefault;
+use schemars::JsonSchema;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{Sender,unbounded};
+use nix::sys::inotify::{Inotify,InitFlags,AddWatchFlags};
+use crate::cmd::{VpiCommand,VpiCommandBody,VpiOrigin};
+use crate::config::VpiConfigSource;
+use crate::error::{Error,Result,ResultExt,WatchInit};
+
+/// Watcher configuration
+#[derive(DeserializePiecewiseDefault,JsonSchema,Debug,Clone,PartialEq)]
+#[schemars(default,deny_unknown_fields)]
+pub struct VpiWatchConfig {
+    /// Include directories, changes of their .yml files trigger a reload
+    pub dirs: Vec<String>,
+    /// Quiet time in ms after the last change before reloading
+    pub debounce: u32,
+}
+
+impl Default for VpiWatchConfig {
+    fn default() -> Self {
+        VpiWatchConfig {
+            dirs: vec!(),
+            debounce: 500,
+        }
+    }
+}
+
+/// Config error with the line & column of a parse error
+pub fn describe_error(e: &Error) -> String {
+    match e {
+        Error::ParseConfig { filename, source } => match source.location() {
+            Some(loc) => format!("{} line {} column {}: {}",filename.display(),loc.line(),loc.column(),source),
+            None => e.to_string(),
+        },
+        _ => e.to_string(),
+    }
+}
+
+/// Check if a file name is a YAML file
+fn is_yaml(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.ends_with(".yml") || name.ends_with(".yaml")
+}
+
+/// Start the watcher threads
+pub fn run_watch(cfg: &VpiWatchConfig, cfg_source: &VpiConfigSource, command_sender_orig: &Sender<VpiCommand>) -> Result<()> {
+    let cfg_file = cfg_source.path.as_path();
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC).context(WatchInit { path: cfg_file })?;
+    let mask = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_MOVED_FROM |
+               AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE;
+    let dir = match cfg_file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let cfg_name = cfg_file.file_name().map(|n| n.to_os_string());
+    let cfg_wd = inotify.add_watch(&dir, mask).context(WatchInit { path: &dir })?;
+    let mut include_wds = vec!();
+    let dirs: BTreeSet<PathBuf> = cfg.dirs.iter().map(PathBuf::from).chain(cfg_source.include_dirs()).collect();
+    let cfg_dir_included = dirs.contains(&dir);
+    for d in dirs.iter().filter(|d| **d != dir) {
+        match inotify.add_watch(d, mask) {
+            Ok(wd) => include_wds.push(wd),
+            Err(e) => warn!("Include directory {} not watched: {}",d.display(),e),
+        }
+    }
+
+    // Reader thread: relevant changes
+    let (change_sender,change_receiver) = unbounded::<()>();
+    thread::spawn(move || {
+        loop {
+            match inotify.read_events() {
+                Ok(events) => {
+                    for ev in events {
+                        let relevant = match ev.name {
+                            Some(ref name) if ev.wd == cfg_wd => Some(name) == cfg_name.as_ref() || (cfg_dir_included && is_yaml(name)),
+                            Some(ref name) => include_wds.contains(&ev.wd) && is_yaml(name),
+                            None => false,
+                        };
+                        if relevant && change_sender.send(()).is_err() {
+                            return;
+                        }
+                    }
+                },
+                Err(e) => { error!("Config watcher failed: {}",e); return; }
+            }
+        }
+    });
+
+    // Reload thread: waits for the writes to settle, the reload validates the config
+    let debounce = Duration::from_millis(cfg.debounce as u64);
+    let path = cfg_source.path.clone();
+    let command_sender = command_sender_orig.clone();
+    thread::spawn(move || {
+        for _ in change_receiver.iter() {
+            while change_receiver.recv_timeout(debounce).is_ok() {}
+            info!("Config file {} changed, reloading",path.display());
+            let _=command_sender.send(VpiCommand::new_nbc(VpiCommandBody::ReloadConfig).with_origin(VpiOrigin::Watch,"reload"));
+        }
+    });
+    Ok(())
+}
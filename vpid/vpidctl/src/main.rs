@@ -1,12 +1,15 @@
 use ansi_term::Colour::{Blue, Green, Red, Yellow};
-use serde_json;
-use std::io::{Read, Write};
-use std::os::unix::net::UnixStream;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::mpsc;
 use std::{thread, time};
 use vpi::cmd::{VpiCmd, VpiCmdOutput};
+use vpi::fwdbg::FwEvent;
 use vpi::Vpi;
+use vpid_client::VpidClient;
+use chrono::Local;
 
 #[macro_use]
 extern crate clap;
@@ -42,6 +45,46 @@ fn show_error_json(j: &String) -> ! {
     exit(1);
 }
 
+/// Output of the uart subcommand
+enum UartLine {
+    /// Firmware trace with the board uptime
+    Trace(FwEvent, u32),
+    /// vpid log line
+    Log(String),
+}
+
+/// Forward the lines of the vpid log, waiting for new lines at EOF when `follow`
+fn follow_log(
+    mut src: Box<dyn BufRead + Send>,
+    follow: bool,
+    tx: mpsc::Sender<std::io::Result<UartLine>>,
+) {
+    thread::spawn(move || {
+        let mut line = String::new();
+        loop {
+            match src.read_line(&mut line) {
+                Ok(0) if follow => thread::sleep(time::Duration::from_millis(100)),
+                Ok(0) => break,
+                // Partial line of a file being written
+                Ok(_) if follow && !line.ends_with('\n') => {
+                    thread::sleep(time::Duration::from_millis(100))
+                }
+                Ok(_) => {
+                    let l = UartLine::Log(line.trim_end().to_string());
+                    line.clear();
+                    if tx.send(Ok(l)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+}
+
 fn main() {
     let version = crate_version!();
     let matches = App::new("vpidctl")
@@ -83,8 +126,144 @@ fn main() {
                                        <BINFILE>             '.bin file with the firmware'",
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print the events of the vpid service")
+                .version(version)
+                .args_from_usage(
+                    "-s, --socket=[socket] 'Socket of vpid service'
+                                       -j, --json            'JSON output (one event per line)'
+                                       [EVENTS]...           'Events to watch: button, irq, rpm, recover, crc_resync, fan, rule, key [default: all]'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("uart")
+                .about("Decode firmware debug traces from the board UART")
+                .version(version)
+                .args_from_usage(
+                    "-p, --port=[tty]      'Serial device [default:/dev/ttyS0]'
+                                       -b, --baud=[baud]     'Baud rate [default:9600]'
+                                       -t, --ticks           'Show one second ticks'
+                                       -j, --json            'JSON output (one event per line)'
+                                       -l, --log=[file]      'vpid log to interleave with the traces, - for stdin'",
+                ),
+        )
         .get_matches();
 
+    if let Some(m) = matches.subcommand_matches("uart") {
+        let port = m.value_of("port").unwrap_or("/dev/ttyS0");
+        let baud: u32 = m
+            .value_of("baud")
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(vpi::fwdbg::FW_DBG_BAUD);
+        let ticks = m.is_present("ticks");
+        let json = m.is_present("json");
+        let mut reader =
+            vpi::fwdbg::open(&PathBuf::from(port), baud).unwrap_or_else(|e| show_error(&e));
+        if !json {
+            println!(
+                "{}",
+                Blue.paint(format!("Reading firmware traces from {} at {} bauds...", port, baud))
+            );
+        }
+        // Traces & vpid log lines are printed in arrival order
+        let (tx, rx) = mpsc::channel();
+        if let Some(log) = m.value_of("log") {
+            let src: Box<dyn BufRead + Send> = if log == "-" {
+                Box::new(BufReader::new(std::io::stdin()))
+            } else {
+                let mut file = File::open(log).unwrap_or_else(|e| show_error(&e));
+                file.seek(SeekFrom::End(0))
+                    .unwrap_or_else(|e| show_error(&e));
+                Box::new(BufReader::new(file))
+            };
+            follow_log(src, log != "-", tx.clone());
+        }
+        thread::spawn(move || {
+            while let Some(res) = reader.next() {
+                let tr = res.map(|ev| UartLine::Trace(ev, reader.uptime()));
+                if tx.send(tr).is_err() {
+                    break;
+                }
+            }
+        });
+        for line in rx {
+            let line = line.unwrap_or_else(|e| show_error(&e));
+            // Same timestamp format as vpid log to merge both outputs
+            let ts = Local::now().format("%Y-%m-%d %H:%M:%S,%3f");
+            match line {
+                UartLine::Trace(FwEvent::Tick, _) if !ticks => {}
+                UartLine::Trace(ev, uptime) if json => {
+                    let mut obj = serde_json::to_value(&ev).unwrap_or_default();
+                    obj["timestamp"] = serde_json::Value::from(ts.to_string());
+                    obj["uptime"] = serde_json::Value::from(uptime);
+                    println!("{}", obj);
+                }
+                UartLine::Trace(ev, uptime) => {
+                    let txt = match ev {
+                        FwEvent::I2cRecover { .. }
+                        | FwEvent::Watchdog { .. }
+                        | FwEvent::WatchdogCount { .. } => Red.paint(ev.to_string()),
+                        FwEvent::Boot { .. } | FwEvent::Transition { .. } => {
+                            Yellow.paint(ev.to_string())
+                        }
+                        FwEvent::Unknown { .. } => Blue.paint(ev.to_string()),
+                        _ => Green.paint(ev.to_string()),
+                    };
+                    println!("{} FW [{}s] {}", ts, uptime, txt);
+                }
+                UartLine::Log(l) if json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({ "Log": l, "timestamp": ts.to_string() })
+                    );
+                }
+                // vpid log lines carry their own timestamp
+                UartLine::Log(l) => println!("{}", l),
+            }
+        }
+        show_success("UART closed", json);
+    }
+
+    if let Some(m) = matches.subcommand_matches("watch") {
+        let socket = m.value_of("socket").unwrap_or(vpid_client::VPID_SOCKET);
+        let json = m.is_present("json");
+        let events: Vec<&str> = m
+            .values_of("EVENTS")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let sub = VpidClient::new(socket)
+            .subscribe(&events)
+            .unwrap_or_else(|e| show_error(&e));
+        if !json {
+            println!(
+                "{}",
+                Blue.paint(format!("Watching vpid events on {}...", socket))
+            );
+        }
+        for res in sub {
+            let mut ev = res.unwrap_or_else(|e| show_error(&e));
+            let ts = Local::now().format("%Y-%m-%d %H:%M:%S,%3f");
+            if json {
+                ev["timestamp"] = serde_json::Value::from(ts.to_string());
+                println!("{}", ev);
+            } else {
+                let name = ev
+                    .as_object_mut()
+                    .and_then(|o| o.remove("event"))
+                    .and_then(|n| n.as_str().map(|n| n.to_string()))
+                    .unwrap_or_default();
+                let txt = match name.as_str() {
+                    "recover" | "crc_resync" => Red.paint(name),
+                    "button" | "irq" => Yellow.paint(name),
+                    _ => Green.paint(name),
+                };
+                println!("{} {} {}", ts, txt, ev);
+            }
+        }
+        show_success("vpid closed the connection", json);
+    }
+
     if let Some(m) = matches.subcommand_matches("firmware") {
         let dev = m.value_of("device").unwrap_or("/dev/i2c-1");
         let addr: u8 = m
@@ -238,30 +417,13 @@ fn main() {
             let arv: Vec<&str> = ar.collect();
             args = arv.join(" ");
         }
-        match UnixStream::connect(socket_name) {
-            Ok(mut stream) => {
-                if let Err(e) = stream.write_fmt(format_args!("{} {}\n", cmd, args)) {
-                    show_error(&e);
-                }
-                if let Err(e) = stream.flush() {
-                    show_error(&e);
-                }
-                let mut resp = String::new();
-                if stream.read_to_string(&mut resp).is_ok() {
-                    let objr: serde_json::Result<serde_json::Value> =
-                        serde_json::from_str(resp.as_str());
-                    if let Ok(obj) = objr {
-                        if obj["result"].as_bool().unwrap_or(false) {
-                            show_success_json(&resp, quiet);
-                        } else {
-                            show_error_json(&resp);
-                        }
-                    } else {
-                        println!("{}", resp);
-                        show_error(&objr.err().unwrap());
-                    }
+        let client = VpidClient::new(socket_name);
+        match client.request(format!("{} {}", cmd, args).as_str()) {
+            Ok(resp) => {
+                if resp["result"].as_bool().unwrap_or(false) {
+                    show_success_json(&resp.to_string(), quiet);
                 } else {
-                    show_error_str("vpid did not responded or timeout occurred");
+                    show_error_json(&resp.to_string());
                 }
             }
             Err(e) => show_error(&e),